@@ -2,12 +2,21 @@ use std::io::{BufWriter, Read, Write};
 use std::time::Instant;
 use std::{fs::File, path::PathBuf};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 
+use trident::build::merge::IndexMerger;
+use trident::build::parallel::ParallelIndexBuilder;
+use trident::build::shard::ShardedIndexBuilder;
 use trident::build::stats::IndexStats;
 use trident::build::IndexBuilder;
-use trident::index::Index;
+use trident::index::layered::LayeredIndex;
+use trident::index::matches::{DocMatch, Match};
+use trident::index::sharded::ShardedIndex;
+use trident::index::{DocIterator, Index, MatrixLayout};
+use trident::query::regex::RegexQuery;
+use trident::query::{Case, Filter, Filtered, IgnoreCase, SearchQuery};
+use trident::DocID;
 use walkdir::WalkDir;
 
 #[derive(Parser, Debug)]
@@ -20,26 +29,103 @@ pub struct Cli {
 pub enum Command {
     Index(IndexArgs),
     Search(SearchArgs),
+    Merge(MergeArgs),
+    Delete(DeleteArgs),
+    Verify(VerifyArgs),
 }
 
 #[derive(Parser, Debug)]
 pub struct IndexArgs {
     #[clap(short = 'o')]
     pub output_file: Option<PathBuf>,
+    #[clap(short = 'c', long)]
+    pub store_content: bool,
+    // Metadata to attach to every indexed doc, as key:value
+    #[clap(short = 'm', long, value_parser = parse_filter)]
+    pub metadata: Vec<Filter>,
+    // Store each posting's successor matrix grouped by successor rather than by doc
+    #[clap(long)]
+    pub successor_major: bool,
+    // Write a trigram presence table if the index has at least this many unique trigrams
+    #[clap(long)]
+    pub presence_table_min_trigrams: Option<usize>,
+    // The approximate memory to use for postings before spilling them to disk, such as "2GB"
+    #[clap(long, value_parser = parse_bytes)]
+    pub memory_budget: Option<usize>,
+    // The directory to spill postings to, instead of the system temporary directory
+    #[clap(long)]
+    pub spill_dir: Option<PathBuf>,
+    // The number of threads to extract trigrams and serialize postings with
+    #[clap(short = 'j', long, default_value_t = 1)]
+    pub threads: usize,
+    // Split the index into shards of at most this many docs, listed in a manifest at the output
+    // file
+    #[clap(long, requires = "output_file")]
+    pub max_shard_docs: Option<usize>,
+    // Split the index into shards of at most this much content, such as "1GB"
+    #[clap(long, value_parser = parse_bytes, requires = "output_file")]
+    pub max_shard_bytes: Option<usize>,
     pub dir: PathBuf,
 }
 
 #[derive(Parser, Debug)]
 pub struct SearchArgs {
+    #[clap(short = 'r', long)]
+    pub regex: bool,
+    #[clap(short = 'i', long)]
+    pub ignore_case: bool,
+    #[clap(short = 'C', long, default_value_t = 0)]
+    pub context: usize,
+    // Metadata filters to apply in addition to any in the query, as key:value
+    #[clap(short = 'f', long, value_parser = parse_filter)]
+    pub filter: Vec<Filter>,
+    // Delta indexes to search along with the index, in the order they were appended
+    #[clap(short = 'd', long)]
+    pub delta: Vec<PathBuf>,
+    // The index path is a shard manifest, and every shard is searched in parallel
+    #[clap(short = 's', long, conflicts_with = "delta")]
+    pub sharded: bool,
     pub index_path: PathBuf,
     pub query: String,
 }
 
+#[derive(Parser, Debug)]
+pub struct DeleteArgs {
+    // Delta indexes appended to the index, which are also searched for the docs
+    #[clap(short = 'd', long)]
+    pub delta: Vec<PathBuf>,
+    pub index_path: PathBuf,
+    // The names of the docs to delete
+    #[clap(required = true)]
+    pub doc_names: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct VerifyArgs {
+    #[clap(required = true)]
+    pub index_paths: Vec<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MergeArgs {
+    #[clap(short = 'o')]
+    pub output_file: PathBuf,
+    #[clap(long)]
+    pub successor_major: bool,
+    #[clap(short = 'j', long, default_value_t = 1)]
+    pub threads: usize,
+    #[clap(required = true)]
+    pub index_paths: Vec<PathBuf>,
+}
+
 fn main() -> Result<()> {
     let args = Cli::try_parse()?;
     match args.cmd {
         Command::Index(a) => index(a),
         Command::Search(a) => search(a),
+        Command::Merge(a) => merge(a),
+        Command::Delete(a) => delete(a),
+        Command::Verify(a) => verify(a),
     }
 }
 
@@ -49,32 +135,116 @@ fn index(args: IndexArgs) -> Result<()> {
         .filter_map(|d| d.ok())
         .filter(|d| d.file_type().is_file());
 
-    let mut builder = IndexBuilder::new();
-    let mut buf = String::new();
-    for doc in docs {
-        buf.clear();
-        let mut f = File::open(doc.path())?;
-        if let Err(e) = f.read_to_string(&mut buf) {
-            println!("skipping {:?}: {}", doc.path(), e);
-        };
-        buf.make_ascii_lowercase();
-        builder.add_doc(buf.as_bytes())?;
+    let matrix_layout = match args.successor_major {
+        true => MatrixLayout::SuccessorMajor,
+        false => MatrixLayout::DocMajor,
+    };
+    let (store_content, threads) = (args.store_content, args.threads);
+    let (memory_budget, spill_dir) = (args.memory_budget, args.spill_dir);
+    let presence_table_min_trigrams = args.presence_table_min_trigrams;
+    let new_builder = move || {
+        let mut builder = IndexBuilder::new()
+            .store_content(store_content)
+            .matrix_layout(matrix_layout)
+            .threads(threads);
+        if let Some(budget) = memory_budget {
+            builder = builder.memory_budget(budget);
+        }
+        if let Some(trigrams) = presence_table_min_trigrams {
+            builder = builder.presence_table_min_trigrams(trigrams);
+        }
+        if let Some(dir) = spill_dir.clone() {
+            builder = builder.spill_dir(dir);
+        }
+        builder
+    };
+    let metadata = args
+        .metadata
+        .iter()
+        .map(|f| (f.key.as_str(), f.value.as_str()))
+        .collect::<Vec<_>>();
+    let read_docs = |add_doc: &mut dyn FnMut(&str, &[u8]) -> Result<()>| -> Result<()> {
+        let mut buf = String::new();
+        for doc in docs {
+            buf.clear();
+            let mut f = File::open(doc.path())?;
+            if let Err(e) = f.read_to_string(&mut buf) {
+                println!("skipping {:?}: {}", doc.path(), e);
+            };
+            add_doc(&doc.path().to_string_lossy(), buf.as_bytes())?;
+        }
+        Ok(())
+    };
+
+    if args.max_shard_docs.is_some() || args.max_shard_bytes.is_some() {
+        let mut builder = ShardedIndexBuilder::new(&args.output_file.unwrap(), new_builder);
+        if let Some(docs) = args.max_shard_docs {
+            builder = builder.max_shard_docs(docs);
+        }
+        if let Some(bytes) = args.max_shard_bytes {
+            builder = builder.max_shard_bytes(bytes);
+        }
+        read_docs(&mut |name, content| builder.add_doc_with_metadata(name, &metadata, content))?;
+        let stats = builder.build()?;
+        let shards = stats.len();
+        for (i, stats) in stats.into_iter().enumerate() {
+            println!("\nShard {} of {}:", i + 1, shards);
+            summarize_stats(stats);
+        }
+        return Ok(());
     }
 
+    let builder = ParallelIndexBuilder::new(new_builder());
+    let adder = builder.handle();
+    read_docs(&mut |name, content| adder.add_doc_with_metadata(name, &metadata, content))?;
+    drop(adder);
+
     let stats = match args.output_file {
         Some(path) => {
             let mut f = BufWriter::new(File::create(path)?);
-            builder.build(&mut f)?
+            let stats = builder.build(&mut f)?;
+            f.into_inner()?;
+            stats
         }
         None => {
             let mut stdout = std::io::stdout().lock();
-            builder.build(&mut stdout)?
+            let stats = builder.build(&mut stdout)?;
+            stdout.flush()?;
+            stats
         }
     };
     summarize_stats(stats);
     Ok(())
 }
 
+fn merge(args: MergeArgs) -> Result<()> {
+    let indexes = args
+        .index_paths
+        .iter()
+        .map(|path| Ok(Index::new(File::open(path)?)?))
+        .collect::<Result<Vec<_>>>()?;
+
+    let matrix_layout = match args.successor_major {
+        true => MatrixLayout::SuccessorMajor,
+        false => MatrixLayout::DocMajor,
+    };
+    // Content can only be kept if every index has it
+    let builder = IndexBuilder::new()
+        .store_content(indexes.iter().all(|index| index.has_content()))
+        .matrix_layout(matrix_layout)
+        .threads(args.threads);
+    let mut merger = IndexMerger::new(builder);
+    for index in indexes.iter() {
+        merger.add_index(index)?;
+    }
+
+    let mut f = BufWriter::new(File::create(args.output_file)?);
+    let stats = merger.build(&mut f)?;
+    f.into_inner()?;
+    summarize_stats(stats);
+    Ok(())
+}
+
 fn summarize_stats(stats: IndexStats) {
     let index_size = stats.build.total_size_bytes();
     let content_size = stats.extract.doc_bytes;
@@ -104,22 +274,266 @@ fn summarize_stats(stats: IndexStats) {
     let successors_ratio = stats.build.postings_sum.successors.bytes as f64 / index_size as f64;
     println!("\tSuccessors: {:.3}", successors_ratio);
 
+    let other_layout_ratio =
+        stats.build.postings_sum.other_layout_successors_bytes as f64 / index_size as f64;
+    println!(
+        "\t(Successors in the other matrix layout: {:.3})",
+        other_layout_ratio
+    );
+
     let unique_docs_ratio = stats.build.postings_sum.unique_docs.bytes as f64 / index_size as f64;
     println!("\tUnique Docs: {:.3}", unique_docs_ratio);
 
     let posting_offsets_ratio = stats.build.posting_offsets_bytes as f64 / index_size as f64;
     println!("\tPosting Offsets: {:.3}", posting_offsets_ratio);
 
+    let presence_table_ratio = stats.build.presence_table_bytes as f64 / index_size as f64;
+    println!("\tPresence Table: {:.3}", presence_table_ratio);
+
+    let content_ratio = stats.build.content_bytes as f64 / index_size as f64;
+    println!("\tContent: {:.3}", content_ratio);
+
+    let doc_names_ratio = stats.build.doc_name_bytes as f64 / index_size as f64;
+    println!("\tDoc Names: {:.3}", doc_names_ratio);
+
+    let metadata_ratio = stats.build.metadata_bytes as f64 / index_size as f64;
+    println!("\tMetadata: {:.3}", metadata_ratio);
+
     println!("Doc count: {}", stats.extract.num_docs);
     println!("Unique trigram count: {}", stats.extract.unique_trigrams);
+    if stats.extract.spilled_runs > 0 {
+        println!("Spilled runs: {}", stats.extract.spilled_runs);
+    }
 }
 
 fn search(args: SearchArgs) -> Result<()> {
-    let index_file = File::open(args.index_path)?;
-    let index = Index::new(index_file)?;
-    let opened = Instant::now();
-    let found = index.candidates(args.query.as_bytes()).count();
-    println!("{} results in {:0.2?}\n", found, opened.elapsed());
+    match args.sharded {
+        true => {
+            let index = ShardedIndex::open(&args.index_path)?;
+            search_with(&index, args, Instant::now())
+        }
+        false => {
+            let index = LayeredIndex::open(&args.index_path, &args.delta)?;
+            search_with(&index, args, Instant::now())
+        }
+    }
+}
+
+fn search_with<S: Searchable>(index: &S, args: SearchArgs, opened: Instant) -> Result<()> {
+    let case = match args.ignore_case {
+        true => Case::Insensitive,
+        false => Case::Sensitive,
+    };
+    match (args.regex, case) {
+        (true, _) => {
+            let query = Filtered {
+                filters: args.filter,
+                query: Some(RegexQuery::with_case(&args.query, case)?),
+            };
+            search_index(index, &query, args.context, opened)
+        }
+        (false, Case::Sensitive) => {
+            let mut query = Filtered::parse(&args.query)?;
+            query.filters.extend(args.filter);
+            search_index(index, &query, args.context, opened)
+        }
+        (false, Case::Insensitive) => {
+            let Filtered { mut filters, query } = Filtered::parse(&args.query)?;
+            filters.extend(args.filter);
+            let query = Filtered {
+                filters,
+                query: query.as_ref().map(IgnoreCase::new),
+            };
+            search_index(index, &query, args.context, opened)
+        }
+    }
+}
+
+fn search_index<S: Searchable, Q: SearchQuery + Sync>(
+    index: &S,
+    query: &Q,
+    context: usize,
+    opened: Instant,
+) -> Result<()> {
+    if !index.has_content() {
+        // Without stored content, we can only count the candidates
+        let found = index.count_candidates(query)?;
+        println!("{} candidates in {:0.2?}\n", found, opened.elapsed());
+        return Ok(());
+    }
+
+    let doc_matches = index.search_matches(query, context)?;
+    let elapsed = opened.elapsed();
 
+    let mut stdout = std::io::stdout().lock();
+    for doc_match in doc_matches.iter() {
+        let name = index.doc_name(doc_match.doc_id)?;
+        print_doc_match(&mut stdout, &name, doc_match, context)?;
+    }
+    println!("{} results in {:0.2?}\n", doc_matches.len(), elapsed);
+
+    Ok(())
+}
+
+// Counts the docs of a stream of candidates, failing if the stream was cut short by an error
+fn count_docs(mut candidates: Box<dyn DocIterator + '_>) -> Result<usize> {
+    let count = candidates.by_ref().count();
+    match candidates.take_error() {
+        Some(err) => Err(err.into()),
+        None => Ok(count),
+    }
+}
+
+// The ways of searching that both layered and sharded indexes support
+trait Searchable {
+    fn has_content(&self) -> bool;
+    fn count_candidates<Q: SearchQuery>(&self, query: &Q) -> Result<usize>;
+    fn search_matches<Q: SearchQuery + Sync>(
+        &self,
+        query: &Q,
+        context: usize,
+    ) -> Result<Vec<DocMatch>>;
+    fn doc_name(&self, doc_id: DocID) -> Result<String>;
+}
+
+impl Searchable for LayeredIndex<File> {
+    fn has_content(&self) -> bool {
+        self.has_content()
+    }
+
+    fn count_candidates<Q: SearchQuery>(&self, query: &Q) -> Result<usize> {
+        count_docs(self.candidates(query))
+    }
+
+    fn search_matches<Q: SearchQuery + Sync>(
+        &self,
+        query: &Q,
+        context: usize,
+    ) -> Result<Vec<DocMatch>> {
+        self.search_matches(query, context)
+    }
+
+    fn doc_name(&self, doc_id: DocID) -> Result<String> {
+        self.doc_name(doc_id)
+    }
+}
+
+impl Searchable for ShardedIndex<File> {
+    fn has_content(&self) -> bool {
+        self.has_content()
+    }
+
+    fn count_candidates<Q: SearchQuery>(&self, query: &Q) -> Result<usize> {
+        count_docs(self.candidates(query))
+    }
+
+    fn search_matches<Q: SearchQuery + Sync>(
+        &self,
+        query: &Q,
+        context: usize,
+    ) -> Result<Vec<DocMatch>> {
+        self.search_matches(query, context)
+    }
+
+    fn doc_name(&self, doc_id: DocID) -> Result<String> {
+        self.doc_name(doc_id)
+    }
+}
+
+fn delete(args: DeleteArgs) -> Result<()> {
+    let mut index = LayeredIndex::open(&args.index_path, &args.delta)?;
+    for name in args.doc_names.iter() {
+        match index.delete_named(name)? {
+            0 => println!("{} not found", name),
+            n => println!("deleted {} ({} docs)", name, n),
+        }
+    }
+    index.write_tombstones(&args.index_path, &args.delta)
+}
+
+fn verify(args: VerifyArgs) -> Result<()> {
+    let mut corrupt = 0;
+    for path in args.index_paths.iter() {
+        let problems = File::open(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(Index::new(file)?.verify()?))
+            .unwrap_or_else(|e| vec![format!("{:#}", e)]);
+        if problems.is_empty() {
+            println!("{}: ok", path.display());
+            continue;
+        }
+        corrupt += 1;
+        println!("{}: {} problems", path.display(), problems.len());
+        for problem in problems {
+            println!("\t{}", problem);
+        }
+    }
+
+    match corrupt {
+        0 => Ok(()),
+        n => Err(anyhow!(
+            "{} of {} indexes are corrupt",
+            n,
+            args.index_paths.len()
+        )),
+    }
+}
+
+fn parse_filter(s: &str) -> Result<Filter> {
+    match s.split_once(':') {
+        Some((key, value)) if !key.is_empty() => Ok(Filter {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+        _ => Err(anyhow!("expected key:value, got {:?}", s)),
+    }
+}
+
+fn parse_bytes(s: &str) -> Result<usize> {
+    bytefmt::parse(s)
+        .map(|n| n as usize)
+        .map_err(|e| anyhow!("invalid size {:?}: {}", s, e))
+}
+
+// Prints the matching lines of a doc like grep, with context lines marked by a '-' instead of a
+// ':' and non-contiguous groups of lines separated by "--".
+fn print_doc_match<W: Write>(
+    w: &mut W,
+    name: &str,
+    doc_match: &DocMatch,
+    context: usize,
+) -> Result<()> {
+    let mut matches = doc_match.matches.iter().collect::<Vec<&Match>>();
+    matches.dedup_by_key(|m| m.line_number);
+
+    let mut last_line = 0;
+    for (i, m) in matches.iter().enumerate() {
+        let first_line = m.line_number - m.before.len();
+        if context > 0 && last_line > 0 && first_line > last_line + 1 {
+            writeln!(w, "--")?;
+        }
+
+        for (n, line) in (first_line..).zip(m.before.iter()) {
+            if n > last_line {
+                write!(w, "{}-{}-", name, n)?;
+                w.write_all(line)?;
+                writeln!(w)?;
+            }
+        }
+
+        write!(w, "{}:{}:{}:", name, m.line_number, m.column)?;
+        w.write_all(&m.line)?;
+        writeln!(w)?;
+        last_line = m.line_number;
+
+        // Stop before the next match's line, which is printed as a match instead
+        let next_line = matches.get(i + 1).map_or(usize::MAX, |n| n.line_number);
+        for (n, line) in (m.line_number + 1..next_line).zip(m.after.iter()) {
+            write!(w, "{}-{}-", name, n)?;
+            w.write_all(line)?;
+            writeln!(w)?;
+            last_line = n;
+        }
+    }
     Ok(())
 }
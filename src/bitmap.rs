@@ -0,0 +1,247 @@
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::build::serialize::StreamWriter;
+use crate::index::DocIterator;
+use crate::DocID;
+
+// A fixed-size set of doc IDs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocBitmap {
+    words: Vec<u64>,
+    len: u32,
+}
+
+impl DocBitmap {
+    // Creates an empty bitmap that can hold the doc IDs in 0..len
+    pub fn new(len: u32) -> Self {
+        Self {
+            words: vec![0; (len as usize).div_ceil(64)],
+            len,
+        }
+    }
+
+    // Creates a bitmap that holds every doc ID in 0..len
+    pub fn full(len: u32) -> Self {
+        let mut bitmap = Self {
+            words: vec![u64::MAX; (len as usize).div_ceil(64)],
+            len,
+        };
+        let rem = len % 64;
+        if rem != 0 {
+            *bitmap.words.last_mut().unwrap() = (1 << rem) - 1;
+        }
+        bitmap
+    }
+
+    pub fn from_doc_ids(len: u32, doc_ids: impl IntoIterator<Item = DocID>) -> Self {
+        let mut bitmap = Self::new(len);
+        for doc_id in doc_ids {
+            bitmap.insert(doc_id);
+        }
+        bitmap
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&w| w == 0)
+    }
+
+    pub fn insert(&mut self, doc_id: DocID) {
+        assert!(doc_id < self.len);
+        self.words[doc_id as usize / 64] |= 1 << (doc_id % 64);
+    }
+
+    pub fn contains(&self, doc_id: DocID) -> bool {
+        doc_id < self.len && self.words[doc_id as usize / 64] & (1 << (doc_id % 64)) != 0
+    }
+
+    pub fn intersect_with(&mut self, other: &DocBitmap) {
+        for (i, word) in self.words.iter_mut().enumerate() {
+            *word &= other.words.get(i).copied().unwrap_or(0);
+        }
+    }
+
+    pub fn union_with(&mut self, other: &DocBitmap) {
+        for (word, other) in self.words.iter_mut().zip(other.words.iter()) {
+            *word |= other;
+        }
+    }
+
+    pub fn difference_with(&mut self, other: &DocBitmap) {
+        for (word, other) in self.words.iter_mut().zip(other.words.iter()) {
+            *word &= !other;
+        }
+    }
+
+    // Reads a bitmap written with `write_to`
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Self> {
+        let len = r.read_u32::<LittleEndian>()?;
+        let mut bitmap = Self::new(len);
+        for word in bitmap.words.iter_mut() {
+            *word = r.read_u64::<LittleEndian>()?;
+        }
+        if bitmap.iter().any(|doc_id| doc_id >= len) {
+            return Err(anyhow!("bitmap contains doc IDs past its length"));
+        }
+        Ok(bitmap)
+    }
+
+    // Returns the number of doc IDs in the bitmap
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    // Returns an iterator over the doc IDs in the bitmap in ascending order
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self.words.iter().copied())
+    }
+}
+
+// Writes the length of the bitmap followed by its words
+impl StreamWriter for DocBitmap {
+    fn write_to<W: Write>(&self, w: &mut W) -> Result<usize> {
+        w.write_u32::<LittleEndian>(self.len)?;
+        for word in self.words.iter() {
+            w.write_u64::<LittleEndian>(*word)?;
+        }
+        Ok(4 + self.words.len() * 8)
+    }
+}
+
+impl IntoIterator for DocBitmap {
+    type Item = DocID;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Words::new(self.words.into_iter())
+    }
+}
+
+// An owning iterator over the doc IDs in a bitmap in ascending order
+pub type IntoIter = Words<std::vec::IntoIter<u64>>;
+
+// A borrowing iterator over the doc IDs in a bitmap in ascending order
+pub type Iter<'a> = Words<std::iter::Copied<std::slice::Iter<'a, u64>>>;
+
+// Iterates over the doc IDs set in a sequence of bitmap words
+pub struct Words<W> {
+    words: W,
+    base: u64,
+    word: u64,
+}
+
+impl<W: Iterator<Item = u64>> Words<W> {
+    fn new(words: W) -> Self {
+        Self {
+            words,
+            base: 0,
+            word: 0,
+        }
+    }
+}
+
+impl<W: Iterator<Item = u64>> Iterator for Words<W> {
+    type Item = DocID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.word == 0 {
+            self.word = self.words.next()?;
+            self.base += 64;
+        }
+        let bit = self.word.trailing_zeros();
+        self.word &= self.word - 1;
+        Some((self.base - 64) as DocID + bit)
+    }
+}
+
+impl<W: Iterator<Item = u64>> DocIterator for Words<W> {
+    // Skips straight to the word that holds the target
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        let target = target as u64;
+        let next_word = self.base / 64;
+        if target / 64 >= next_word {
+            self.word = self.words.nth((target / 64 - next_word) as usize)?;
+            self.base = (target / 64 + 1) * 64;
+        }
+        if target >= self.base - 64 {
+            self.word &= u64::MAX << (target - (self.base - 64));
+        }
+        self.next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    quickcheck! {
+        fn bitmap_roundtrip(doc_ids: Vec<u16>) -> bool {
+            let mut expected = doc_ids.iter().map(|&d| d as DocID).collect::<Vec<_>>();
+            expected.sort();
+            expected.dedup();
+
+            let bitmap = DocBitmap::from_doc_ids(u16::MAX as u32 + 1, expected.iter().copied());
+            bitmap.iter().collect::<Vec<_>>() == expected
+                && bitmap.count() == expected.len()
+                && bitmap.into_iter().collect::<Vec<_>>() == expected
+        }
+    }
+
+    #[test]
+    fn test_full() {
+        let bitmap = DocBitmap::full(70);
+        assert_eq!(bitmap.count(), 70);
+        assert!(bitmap.contains(69));
+        assert!(!bitmap.contains(70));
+    }
+
+    // Checks that advancing through the targets in order finds the same docs as a linear scan
+    fn check_advance_to(mut iter: impl DocIterator, expected: &[DocID], targets: &[u16]) -> bool {
+        let mut pos = 0;
+        for &target in targets {
+            let found = iter.advance_to(target as DocID);
+            match expected[pos..].iter().position(|&d| d >= target as DocID) {
+                Some(p) if found == Some(expected[pos + p]) => pos += p + 1,
+                None if found.is_none() => return true,
+                _ => return false,
+            }
+        }
+        iter.collect::<Vec<_>>() == expected[pos..]
+    }
+
+    quickcheck! {
+        fn bitmap_advance_to(doc_ids: Vec<u16>, targets: Vec<u16>) -> bool {
+            let bitmap = DocBitmap::from_doc_ids(u16::MAX as u32 + 1, doc_ids.iter().map(|&d| d as DocID));
+            let expected = bitmap.iter().collect::<Vec<_>>();
+            let mut targets = targets;
+            targets.sort();
+
+            check_advance_to(bitmap.iter(), &expected, &targets)
+                && check_advance_to(bitmap.into_iter(), &expected, &targets)
+        }
+    }
+
+    quickcheck! {
+        fn bitmap_serialize_roundtrip(doc_ids: Vec<u16>) -> bool {
+            let bitmap = DocBitmap::from_doc_ids(u16::MAX as u32 - 7, doc_ids.iter().map(|&d| d as DocID % (u16::MAX as u32 - 7)));
+            let mut buf = Vec::new();
+            bitmap.write_to(&mut buf).unwrap();
+            DocBitmap::read_from(&mut buf.as_slice()).unwrap() == bitmap
+        }
+    }
+
+    #[test]
+    fn test_intersect_with() {
+        let mut a = DocBitmap::from_doc_ids(200, [1, 5, 70, 130]);
+        let b = DocBitmap::from_doc_ids(200, [5, 70, 199]);
+        a.intersect_with(&b);
+        assert_eq!(a.iter().collect::<Vec<_>>(), vec![5, 70]);
+    }
+}
@@ -0,0 +1,244 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use anyhow::{anyhow, Result};
+
+use super::spill::Postings;
+use super::stats::IndexStats;
+use super::IndexBuilder;
+use crate::bitmap::DocBitmap;
+use crate::error::TridentError;
+use crate::index::Index;
+use crate::ioutil::{Len, ReadAt};
+use crate::{DocID, Trigram};
+
+// Merges indexes into one without re-reading the source docs. The docs of each index are
+// renumbered to follow the docs of the indexes added before it, and the postings of every index
+// are merged per trigram as a stream when the merged index is built. The builder's settings, such
+// as the matrix layout, apply to the merged index.
+pub struct IndexMerger<'a> {
+    builder: IndexBuilder,
+    postings: Vec<Box<dyn Iterator<Item = (Trigram, Postings)> + 'a>>,
+
+    // The first error reading the postings of an index, which ends its stream of postings early
+    error: Rc<RefCell<Option<TridentError>>>,
+}
+
+impl<'a> IndexMerger<'a> {
+    // Creates a merger that writes the merged index with `builder`. If the builder stores
+    // content, every merged index must store content.
+    pub fn new(builder: IndexBuilder) -> Self {
+        Self {
+            builder,
+            postings: Vec::new(),
+            error: Rc::default(),
+        }
+    }
+
+    // Adds the docs of an index that are not deleted
+    pub fn add_index<R: ReadAt + Len>(&mut self, index: &'a Index<R>) -> Result<()> {
+        self.add_index_without(index, index.deleted())
+    }
+
+    // Adds the docs of an index except those in `deleted`, such as tombstones that are kept apart
+    // from the index. The remaining docs are renumbered to close the gaps left by deleted docs.
+    pub fn add_index_without<R: ReadAt + Len>(
+        &mut self,
+        index: &'a Index<R>,
+        deleted: Option<&DocBitmap>,
+    ) -> Result<()> {
+        if self.builder.store_content && !index.has_content() {
+            return Err(anyhow!(
+                "cannot store content from an index without content"
+            ));
+        }
+        if matches!(deleted, Some(deleted) if deleted.len() != index.num_docs()) {
+            return Err(anyhow!("deleted docs do not match the index"));
+        }
+
+        let offset = self.builder.num_docs as DocID;
+        offset
+            .checked_add(index.num_docs())
+            .ok_or_else(|| anyhow!("too many docs to merge"))?;
+
+        // The new ID of each doc, or None if it is deleted
+        let mut new_ids = Vec::with_capacity(index.num_docs() as usize);
+        for (doc_id, name) in (0..).zip(index.doc_names()) {
+            if matches!(deleted, Some(deleted) if deleted.contains(doc_id)) {
+                new_ids.push(None);
+                continue;
+            }
+
+            let name = name?;
+            let (content_len, compressed_content) = match self.builder.store_content {
+                true => {
+                    let compressed = index.compressed_content(doc_id)?;
+                    let content_len = compressed
+                        .first_chunk::<4>()
+                        .ok_or_else(|| anyhow!("content of doc {} is missing its size", doc_id))?;
+                    let content_len = u32::from_le_bytes(*content_len);
+                    (content_len as usize, Some(compressed))
+                }
+                false => (0, None),
+            };
+            new_ids.push(Some(self.builder.register_doc(
+                &name,
+                &[],
+                content_len,
+                compressed_content,
+            )));
+        }
+
+        // Indexes are added in order of doc ID, so appending keeps each list of docs sorted
+        for (key, value, doc_ids) in index.metadata()? {
+            let doc_ids: Vec<DocID> = doc_ids
+                .into_iter()
+                .filter_map(|doc_id| new_ids[doc_id as usize])
+                .collect();
+            if !doc_ids.is_empty() {
+                self.builder
+                    .metadata
+                    .entry((key, value))
+                    .or_default()
+                    .extend(doc_ids);
+            }
+        }
+
+        let error = Rc::clone(&self.error);
+        self.postings.push(Box::new(
+            index
+                .postings()
+                .map_while(move |posting| match posting {
+                    Ok(posting) => Some(posting),
+                    Err(err) => {
+                        error.borrow_mut().get_or_insert(err);
+                        None
+                    }
+                })
+                .map(move |(trigram, docs)| {
+                    let docs = docs
+                        .into_iter()
+                        .filter_map(|(doc_id, successors)| {
+                            new_ids[doc_id as usize].map(|doc_id| (doc_id, successors))
+                        })
+                        .collect();
+                    (trigram, docs)
+                })
+                // Drop the trigrams that only deleted docs contained
+                .filter(|(_, docs): &(Trigram, Postings)| !docs.is_empty()),
+        ));
+        Ok(())
+    }
+
+    // Fails if the postings of any index could not be read, in which case the merged index that
+    // was written is incomplete
+    pub fn build<W: Write>(self, w: &mut W) -> Result<IndexStats> {
+        let stats = self.builder.build_with_postings(w, self.postings)?;
+        match self.error.take() {
+            Some(err) => Err(err.into()),
+            None => Ok(stats),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index::MatrixLayout;
+    use crate::ioutil::Mem;
+    use crate::query::Filter;
+
+    #[test]
+    fn test_merge() {
+        let docs: Vec<String> = (0..60)
+            .map(|i| format!("key{:03}; value{}", i % 25, i))
+            .collect();
+        let metadata = |i: usize| [("half", if i < 30 { "first" } else { "second" })];
+
+        let mut builder = IndexBuilder::new().store_content(true);
+        for (i, doc) in docs.iter().enumerate() {
+            builder
+                .add_doc_with_metadata(&format!("doc{}.txt", i), &metadata(i), doc.as_bytes())
+                .unwrap();
+        }
+        let mut expected = Vec::new();
+        builder.build(&mut expected).unwrap();
+
+        // Split the docs across indexes with different layouts
+        let mut indexes = Vec::new();
+        for (range, layout) in [
+            (0..25, MatrixLayout::SuccessorMajor),
+            (25..40, MatrixLayout::DocMajor),
+            (40..60, MatrixLayout::SuccessorMajor),
+        ] {
+            let mut builder = IndexBuilder::new()
+                .store_content(true)
+                .matrix_layout(layout);
+            for i in range {
+                builder
+                    .add_doc_with_metadata(
+                        &format!("doc{}.txt", i),
+                        &metadata(i),
+                        docs[i].as_bytes(),
+                    )
+                    .unwrap();
+            }
+            let mut output = Vec::new();
+            builder.build(&mut output).unwrap();
+            indexes.push(Index::new(Mem(output)).unwrap());
+        }
+
+        let mut merger = IndexMerger::new(IndexBuilder::new().store_content(true));
+        for index in indexes.iter() {
+            merger.add_index(index).unwrap();
+        }
+        let mut output = Vec::new();
+        merger.build(&mut output).unwrap();
+        assert_eq!(output, expected);
+
+        let index = Index::new(Mem(output)).unwrap();
+        let filter = Filter {
+            key: "half".to_string(),
+            value: "second".to_string(),
+        };
+        assert_eq!(index.filter_docs(&[filter]).unwrap().count(), 30);
+        assert_eq!(index.content(59).unwrap(), docs[59].as_bytes());
+    }
+
+    #[test]
+    fn test_merge_skips_deleted() {
+        let build = |docs: &[(&str, &str)]| {
+            let mut builder = IndexBuilder::new().store_content(true);
+            for (name, content) in docs {
+                builder
+                    .add_doc_with_metadata(name, &[("name", name)], content.as_bytes())
+                    .unwrap();
+            }
+            let mut output = Vec::new();
+            builder.build(&mut output).unwrap();
+            Index::new(Mem(output)).unwrap()
+        };
+
+        let mut first = build(&[("a", "alpha"), ("b", "bravo"), ("c", "charlie")]);
+        first.delete(1).unwrap();
+        let second = build(&[("d", "delta"), ("e", "echo")]);
+        let deleted = DocBitmap::from_doc_ids(2, [0]);
+
+        let mut merger = IndexMerger::new(IndexBuilder::new().store_content(true));
+        merger.add_index(&first).unwrap();
+        merger.add_index_without(&second, Some(&deleted)).unwrap();
+        let mut output = Vec::new();
+        merger.build(&mut output).unwrap();
+
+        let expected = build(&[("a", "alpha"), ("c", "charlie"), ("e", "echo")]);
+        let index = Index::new(Mem(output)).unwrap();
+        assert_eq!(index.num_docs(), 3);
+        assert_eq!(index.metadata().unwrap(), expected.metadata().unwrap());
+        assert!(index
+            .postings()
+            .map(Result::unwrap)
+            .eq(expected.postings().map(Result::unwrap)));
+        assert_eq!(index.content(2).unwrap(), b"echo");
+    }
+}
@@ -1,31 +1,75 @@
+use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::io;
 use std::ops::RangeFrom;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::time::Instant;
 use std::{io::Write, time::Duration};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
+use integer_encoding::VarIntWriter;
+use itertools::Itertools;
 use rustc_hash::{FxHashMap, FxHashSet};
 
-use crate::index::{IndexHeader, PostingHeader};
-use crate::ioutil::Section;
+use crate::index::dictionary::write_presence_table;
+use crate::index::{IndexHeader, MatrixLayout, PostingHeader};
+use crate::ioutil::{ChecksumWriter, Section};
 use crate::Trigram;
 use crate::{DocID, TrigramID};
 
+pub mod merge;
+pub mod parallel;
 pub mod serialize;
+pub mod shard;
+mod spill;
 pub mod stats;
-use serialize::{StreamWriter, U32DeltaCompressor};
+use serialize::{FrontCodedEncoder, MatrixCompressor, StreamWriter, U32DeltaCompressor};
 use stats::{IndexStats, SequenceStats, TrigramPostingStats};
 
+use self::spill::{Postings, Run};
 use self::stats::{BuildStats, ExtractStats};
 
+// The number of postings that each thread serializes before they are written in order
+const POSTINGS_PER_THREAD_BATCH: usize = 256;
+
+// The number of unique trigrams at which an index gets a trigram presence table by default. The
+// table is about 2 MB whatever the index holds, so it only pays for itself once the sorted trigrams
+// are large enough that reading them to load the sample of each block's first trigram is slow.
+const DEFAULT_PRESENCE_TABLE_MIN_TRIGRAMS: usize = 1 << 18;
+
 pub struct IndexBuilder {
     doc_ids: RangeFrom<DocID>,
-    combined: BTreeMap<Trigram, Vec<(DocID, FxHashSet<Trigram>)>>,
+    combined: BTreeMap<Trigram, Postings>,
 
-    // Reusable buffers
-    buf_trigram_set: FxHashSet<Trigram>,
-    buf_u32: Vec<u32>,
+    // The approximate size of `combined`, and the size at which it is spilled to a sorted run in
+    // `spill_dir`. The runs are merged with `combined` when the index is built.
+    combined_bytes: usize,
+    memory_budget: Option<usize>,
+    spill_dir: PathBuf,
+    runs: Vec<Run>,
+
+    // Front-coded doc names
+    names: FrontCodedEncoder,
+
+    // The docs that have each metadata key and value
+    metadata: BTreeMap<(String, String), Vec<DocID>>,
+
+    // How the successor matrix of each posting is ordered
+    matrix_layout: MatrixLayout,
+
+    // The number of unique trigrams at which the index gets a presence table for trigram lookups
+    presence_table_min_trigrams: usize,
+
+    // Compressed doc contents, if they are stored
+    store_content: bool,
+    contents: Vec<u8>,
+    content_ends: Vec<u64>,
+
+    // The number of threads that extract trigrams in a `ParallelIndexBuilder` and serialize
+    // postings in `build`
+    threads: usize,
 
     // Stats
     creation_time: Instant,
@@ -39,8 +83,18 @@ impl Default for IndexBuilder {
         Self {
             doc_ids: 0..,
             combined: BTreeMap::default(),
-            buf_trigram_set: FxHashSet::default(),
-            buf_u32: Vec::default(),
+            combined_bytes: 0,
+            memory_budget: None,
+            spill_dir: std::env::temp_dir(),
+            runs: Vec::new(),
+            names: FrontCodedEncoder::default(),
+            metadata: BTreeMap::default(),
+            matrix_layout: MatrixLayout::DocMajor,
+            presence_table_min_trigrams: DEFAULT_PRESENCE_TABLE_MIN_TRIGRAMS,
+            store_content: false,
+            contents: Vec::new(),
+            content_ends: Vec::new(),
+            threads: 1,
             creation_time: Instant::now(),
             extract_duration: Duration::default(),
             total_doc_bytes: 0,
@@ -54,46 +108,160 @@ impl IndexBuilder {
         Self::default()
     }
 
-    pub fn add_doc(&mut self, content: &[u8]) -> Result<()> {
+    // Sets whether the content of each doc is stored in the index, which allows candidates to be
+    // verified with `Index::search`.
+    pub fn store_content(mut self, store_content: bool) -> Self {
+        self.store_content = store_content;
+        self
+    }
+
+    // Sets how the successor matrix of each posting is ordered. A successor-major matrix makes
+    // searches for a trigram and its successor read only the docs for that successor.
+    pub fn matrix_layout(mut self, matrix_layout: MatrixLayout) -> Self {
+        self.matrix_layout = matrix_layout;
+        self
+    }
+
+    // Sets the number of unique trigrams at which the index gets a presence table, which finds a
+    // trigram's posting with one read instead of a search of the sorted trigrams. Use 0 to always
+    // write the table and `usize::MAX` to never write it.
+    pub fn presence_table_min_trigrams(mut self, trigrams: usize) -> Self {
+        self.presence_table_min_trigrams = trigrams;
+        self
+    }
+
+    // Sets the approximate number of bytes of trigram postings to keep in memory while docs are
+    // added. Without a budget, every posting is kept in memory until the index is built.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    // Sets the directory for the temporary files that postings are spilled to when they exceed the
+    // memory budget. Defaults to the system temporary directory.
+    pub fn spill_dir(mut self, dir: PathBuf) -> Self {
+        self.spill_dir = dir;
+        self
+    }
+
+    // Sets the number of threads that serialize postings in `build` and that extract trigrams in a
+    // `ParallelIndexBuilder`. The index is identical for any number of threads, and 0 is treated as
+    // 1.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // Adds a doc to the index. The name, usually the path of the doc, can be looked up by doc ID
+    // with `Index::doc_name`.
+    pub fn add_doc(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        self.add_doc_with_metadata(name, &[], content)
+    }
+
+    // Adds a doc to the index along with key/value metadata, such as its repository or language,
+    // which queries can filter on with `Index::filter_docs`.
+    pub fn add_doc_with_metadata(
+        &mut self,
+        name: &str,
+        metadata: &[(&str, &str)],
+        content: &[u8],
+    ) -> Result<()> {
         let start = Instant::now();
+        let compressed_content = self
+            .store_content
+            .then(|| lz4_flex::compress_prepend_size(content));
+        let doc_id = self.register_doc(name, metadata, content.len(), compressed_content);
+        self.add_trigrams(doc_id, Self::extract_trigrams(content))?;
+        self.extract_duration += start.elapsed();
+        Ok(())
+    }
+
+    // Assigns the next doc ID to a doc and records everything about it except its trigrams
+    fn register_doc(
+        &mut self,
+        name: &str,
+        metadata: &[(&str, &str)],
+        content_len: usize,
+        compressed_content: Option<Vec<u8>>,
+    ) -> DocID {
+        self.names.push(name.as_bytes());
 
         let doc_id = self.doc_ids.next().unwrap();
-        for (trigram, set) in Self::extract_trigrams(content) {
+        for &(key, value) in metadata {
+            let doc_ids = self
+                .metadata
+                .entry((key.to_string(), value.to_string()))
+                .or_default();
+            if doc_ids.last() != Some(&doc_id) {
+                doc_ids.push(doc_id);
+            }
+        }
+
+        if let Some(compressed_content) = compressed_content {
+            self.contents.extend(compressed_content);
+            self.content_ends.push(self.contents.len() as u64);
+        }
+
+        self.total_doc_bytes += content_len;
+        self.num_docs += 1;
+        doc_id
+    }
+
+    // Adds the trigrams of a registered doc to the postings, spilling them to disk if they
+    // exceed the memory budget. Docs do not have to be added in order of doc ID.
+    fn add_trigrams(
+        &mut self,
+        doc_id: DocID,
+        trigrams: FxHashMap<Trigram, FxHashSet<Trigram>>,
+    ) -> Result<()> {
+        for (trigram, set) in trigrams {
+            // Each successor set is a hash table with a control byte per bucket
+            self.combined_bytes += std::mem::size_of::<(DocID, FxHashSet<Trigram>)>()
+                + set.capacity() * (std::mem::size_of::<Trigram>() + 1);
             match self.combined.get_mut(&trigram) {
                 Some(v) => v.push((doc_id, set)),
                 None => {
+                    self.combined_bytes += std::mem::size_of::<(Trigram, Postings)>();
                     self.combined.insert(trigram, vec![(doc_id, set)]);
                 }
             }
         }
 
-        self.extract_duration += start.elapsed();
-        self.total_doc_bytes += content.len();
-        self.num_docs += 1;
+        if let Some(budget) = self.memory_budget {
+            if self.combined_bytes > budget {
+                self.spill()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the postings in memory to a sorted run on disk
+    fn spill(&mut self) -> Result<()> {
+        let run = Run::write(&self.spill_dir, &self.combined)?;
+        self.runs.push(run);
+        self.combined.clear();
+        self.combined_bytes = 0;
         Ok(())
     }
 
     fn extract_trigrams(content: &[u8]) -> FxHashMap<Trigram, FxHashSet<Trigram>> {
         let mut res: FxHashMap<Trigram, FxHashSet<Trigram>> = FxHashMap::default();
 
-        let mut buf = [0u8; 4];
-        let partial_trigrams = {
-            let bytes = match content {
-                [.., y, z] => {
-                    buf = [*y, *z, 0xFF, 0xFF];
-                    &buf[..4]
-                }
-                [z] => {
-                    buf = [*z, 0xFF, 0xFF, 0xFF];
-                    &buf[..3]
-                }
-                _ => &buf[..0],
-            };
-            bytes.array_windows::<3>().copied().map(Trigram)
-        };
+        // The successor of each trigram is the trigram three bytes later, so pad the end of the
+        // doc to give the last trigrams partial successors. The last two bytes of the doc form
+        // partial trigrams of their own.
+        let mut padded = Vec::with_capacity(content.len() + 3);
+        padded.extend_from_slice(content);
+        padded.extend_from_slice(&[0xFF; 3]);
+        let partial_trigrams = padded
+            .array_windows::<3>()
+            .skip(content.len().saturating_sub(2))
+            .take(content.len().min(2))
+            .copied()
+            .map(Trigram);
 
         let trigrams = content.array_windows::<3>().copied().map(Trigram);
-        let successors = trigrams.clone().skip(3).chain(partial_trigrams.clone());
+        let successors = padded.array_windows::<3>().skip(3).copied().map(Trigram);
 
         for (trigram, successor) in trigrams.zip(successors) {
             match res.get_mut(&trigram) {
@@ -114,6 +282,269 @@ impl IndexBuilder {
         res
     }
 
+    // Serializes the metadata as a count followed by an entry per key and value, sorted by key and
+    // value. Each entry is the length-prefixed key and value, then the count and size of the
+    // compressed list of docs that have them.
+    fn build_metadata(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.write_varint(self.metadata.len())?;
+        for ((key, value), doc_ids) in self.metadata.iter() {
+            buf.write_varint(key.len())?;
+            buf.write_all(key.as_bytes())?;
+            buf.write_varint(value.len())?;
+            buf.write_all(value.as_bytes())?;
+
+            let mut docs = Vec::new();
+            U32DeltaCompressor(doc_ids).write_to(&mut docs)?;
+            buf.write_varint(doc_ids.len())?;
+            buf.write_varint(docs.len())?;
+            buf.write_all(&docs)?;
+        }
+        Ok(buf)
+    }
+
+    pub fn build<W: Write>(self, w: &mut W) -> Result<IndexStats> {
+        self.build_with_postings(w, Vec::new())
+    }
+
+    // Builds the index from the added docs along with postings from other sources, such as the
+    // indexes being merged. Each source must be in trigram order.
+    fn build_with_postings<'a, W: Write>(
+        mut self,
+        w: &mut W,
+        postings: Vec<Box<dyn Iterator<Item = (Trigram, Postings)> + 'a>>,
+    ) -> Result<IndexStats> {
+        let mut w = ChecksumWriter::new(w);
+        let mut extract_stats = ExtractStats {
+            num_docs: self.num_docs,
+            doc_bytes: self.total_doc_bytes,
+            unique_trigrams: 0,
+            spilled_runs: self.runs.len(),
+            extract_time: self.extract_duration,
+        };
+
+        let build_start = Instant::now();
+        let mut build_stats = BuildStats::default();
+        let mut posting_ends: Vec<(Trigram, u64)> = Vec::new();
+        let mut postings_len: u64 = 0;
+
+        // Merge the spilled runs, the postings still in memory, and any other postings by trigram
+        let runs = std::mem::take(&mut self.runs);
+        // The first error reading a spilled run, which ends the run's stream of postings early
+        let run_error: Rc<RefCell<Option<io::Error>>> = Rc::default();
+        let mut sources = runs
+            .iter()
+            .map(|run| {
+                let error = Rc::clone(&run_error);
+                let postings = run.read()?.map_while(move |posting| match posting {
+                    Ok(posting) => Some(posting),
+                    Err(err) => {
+                        error.borrow_mut().get_or_insert(err);
+                        None
+                    }
+                });
+                Ok(Box::new(postings) as Box<dyn Iterator<Item = (Trigram, Postings)>>)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        sources.push(Box::new(std::mem::take(&mut self.combined).into_iter()));
+        sources.extend(postings);
+        let merged = sources
+            .into_iter()
+            .enumerate()
+            .map(|(i, source)| source.map(move |(trigram, docs)| (trigram, i, docs)))
+            .kmerge_by(|a, b| (a.0, a.1) < (b.0, b.1))
+            .map(|(trigram, _, docs)| (trigram, docs))
+            .coalesce(|(a, mut a_docs), (b, b_docs)| {
+                if a == b {
+                    a_docs.extend(b_docs);
+                    Ok((a, a_docs))
+                } else {
+                    Err(((a, a_docs), (b, b_docs)))
+                }
+            })
+            .map(|(trigram, mut docs)| {
+                // Docs extracted in parallel or merged from other indexes may be out of order
+                docs.sort_unstable_by_key(|(doc_id, _)| *doc_id);
+                (trigram, docs)
+            });
+
+        let mut add_posting = |trigram, posting_stats: TrigramPostingStats| {
+            build_stats.add_posting(&posting_stats);
+            postings_len += posting_stats.total_bytes() as u64;
+            posting_ends.push((trigram, postings_len));
+        };
+        let mut posting_builders = (0..self.threads)
+            .map(|_| PostingBuilder::new(self.matrix_layout))
+            .collect::<Vec<_>>();
+        if self.threads == 1 {
+            for (trigram, docs) in merged {
+                add_posting(
+                    trigram,
+                    posting_builders[0].build_posting(&mut w, trigram, &docs)?,
+                );
+            }
+        } else {
+            // Serialize a batch of postings per thread at a time, then write them in order
+            let mut merged = merged.peekable();
+            while merged.peek().is_some() {
+                let batch = merged
+                    .by_ref()
+                    .take(self.threads * POSTINGS_PER_THREAD_BATCH)
+                    .collect::<Vec<_>>();
+                let chunk_len = batch.len().div_ceil(self.threads);
+                let serialized = std::thread::scope(|s| {
+                    let handles = batch
+                        .chunks(chunk_len)
+                        .zip(posting_builders.iter_mut())
+                        .map(|(chunk, posting_builder)| {
+                            s.spawn(move || {
+                                chunk
+                                    .iter()
+                                    .map(|(trigram, docs)| {
+                                        let mut buf = Vec::new();
+                                        let stats = posting_builder
+                                            .build_posting(&mut buf, *trigram, docs)?;
+                                        Ok((*trigram, buf, stats))
+                                    })
+                                    .collect::<Result<Vec<_>>>()
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    handles
+                        .into_iter()
+                        .map(|h| {
+                            h.join()
+                                .map_err(|_| anyhow!("posting serialization thread panicked"))?
+                        })
+                        .collect::<Result<Vec<_>>>()
+                })?;
+
+                for (trigram, buf, posting_stats) in serialized.into_iter().flatten() {
+                    w.write_all(&buf)?;
+                    add_posting(trigram, posting_stats);
+                }
+            }
+        }
+        if let Some(err) = run_error.take() {
+            return Err(anyhow::Error::new(err).context("failed to read spilled postings"));
+        }
+        extract_stats.unique_trigrams = posting_ends.len();
+        let postings_checksum = w.finish_section();
+
+        let mut unique_trigrams_len = 0;
+        for (trigram, _) in posting_ends.iter() {
+            unique_trigrams_len += w.write(&<[u8; 3]>::from(*trigram))?;
+        }
+        let unique_trigrams_checksum = w.finish_section();
+
+        let mut offsets_len = 0;
+        for (_, offset) in posting_ends.iter() {
+            w.write_u64::<LittleEndian>(*offset)?;
+            offsets_len += 8;
+        }
+        let offsets_checksum = w.finish_section();
+
+        let mut presence_len = 0;
+        if posting_ends.len() >= self.presence_table_min_trigrams {
+            let trigrams = posting_ends.iter().map(|(trigram, _)| *trigram);
+            presence_len = write_presence_table(&mut w, trigrams)?;
+        }
+        let presence_checksum = w.finish_section();
+
+        w.write_all(&self.contents)?;
+        let contents_len = self.contents.len() as u64;
+        let contents_checksum = w.finish_section();
+
+        let mut content_ends_len = 0;
+        for end in self.content_ends.iter() {
+            w.write_u64::<LittleEndian>(*end)?;
+            content_ends_len += 8;
+        }
+        let content_ends_checksum = w.finish_section();
+
+        let (names, name_block_ends) = std::mem::take(&mut self.names).finish();
+        w.write_all(&names)?;
+        let names_len = names.len() as u64;
+        let names_checksum = w.finish_section();
+
+        let mut name_block_ends_len = 0;
+        for end in name_block_ends.iter() {
+            w.write_u64::<LittleEndian>(*end)?;
+            name_block_ends_len += 8;
+        }
+        let name_block_ends_checksum = w.finish_section();
+
+        let metadata = self.build_metadata()?;
+        w.write_all(&metadata)?;
+        let metadata_len = metadata.len() as u64;
+        let metadata_checksum = w.finish_section();
+
+        let trigram_posting_ends_offset = postings_len + unique_trigrams_len as u64;
+        let presence_offset = trigram_posting_ends_offset + offsets_len;
+        let contents_offset = presence_offset + presence_len;
+        let names_offset = contents_offset + contents_len + content_ends_len;
+        let header = IndexHeader {
+            num_docs: self.num_docs as u32,
+            matrix_layout: self.matrix_layout,
+            trigram_postings: Section::new(0, postings_len),
+            unique_trigrams: Section::new(postings_len, unique_trigrams_len as u64),
+            trigram_posting_ends: Section::new(trigram_posting_ends_offset, offsets_len),
+            trigram_presence: Section::new(presence_offset, presence_len),
+            contents: Section::new(contents_offset, contents_len),
+            content_ends: Section::new(contents_offset + contents_len, content_ends_len),
+            doc_names: Section::new(names_offset, names_len),
+            doc_name_block_ends: Section::new(names_offset + names_len, name_block_ends_len),
+            metadata: Section::new(names_offset + names_len + name_block_ends_len, metadata_len),
+            checksums: [
+                postings_checksum,
+                unique_trigrams_checksum,
+                offsets_checksum,
+                presence_checksum,
+                contents_checksum,
+                content_ends_checksum,
+                names_checksum,
+                name_block_ends_checksum,
+                metadata_checksum,
+            ],
+        };
+
+        header.write_to(&mut w)?;
+
+        build_stats.posting_offsets_bytes = offsets_len as usize;
+        build_stats.presence_table_bytes = presence_len as usize;
+        build_stats.content_bytes = (contents_len + content_ends_len) as usize;
+        build_stats.doc_name_bytes = (names_len + name_block_ends_len) as usize;
+        build_stats.metadata_bytes = metadata_len as usize;
+        build_stats.build_time = build_start.elapsed();
+
+        Ok(IndexStats {
+            extract: extract_stats,
+            build: build_stats,
+            total_time: self.creation_time.elapsed(),
+        })
+    }
+}
+
+// Serializes postings, reusing its buffers between them
+struct PostingBuilder {
+    matrix_layout: MatrixLayout,
+    buf_trigram_set: FxHashSet<Trigram>,
+    buf_u32: Vec<u32>,
+    buf_entries: Vec<(u32, u32)>,
+    buf_entries_transposed: Vec<(u32, u32)>,
+}
+
+impl PostingBuilder {
+    fn new(matrix_layout: MatrixLayout) -> Self {
+        Self {
+            matrix_layout,
+            buf_trigram_set: FxHashSet::default(),
+            buf_u32: Vec::default(),
+            buf_entries: Vec::default(),
+            buf_entries_transposed: Vec::default(),
+        }
+    }
+
     fn build_unique_successors<W: Write>(
         &mut self,
         w: &mut W,
@@ -143,34 +574,60 @@ impl IndexBuilder {
         ))
     }
 
-    // Called per unique trigram
+    // Called per unique trigram. Writes the successor matrix in the configured layout. Returns the
+    // stats for the whole matrix, the size of its row ends, and the size of the other layout.
     fn build_successors<W: Write>(
         &mut self,
         w: &mut W,
         unique_successors: &[TrigramID],
         docs: &[(DocID, FxHashSet<Trigram>)],
-    ) -> Result<SequenceStats> {
-        self.buf_u32.clear();
-        for (local_doc_id, (doc_id, successors)) in docs.iter().enumerate() {
-            let offset = local_doc_id * unique_successors.len();
-            self.buf_u32.extend(
+    ) -> Result<(SequenceStats, usize, usize)> {
+        self.buf_entries.clear();
+        for (local_doc_id, (_, successors)) in docs.iter().enumerate() {
+            self.buf_entries.extend(
                 successors
                     .iter()
                     .copied()
                     .map(u32::from)
                     .map(|t| unique_successors.binary_search(&t).unwrap() as u32)
-                    .map(|t| t + offset as u32),
+                    .map(|t| (local_doc_id as u32, t)),
             );
-            let l = self.buf_u32.len();
-            self.buf_u32[l - successors.len()..].sort();
+            let l = self.buf_entries.len();
+            self.buf_entries[l - successors.len()..].sort();
         }
 
-        let compressed_size = U32DeltaCompressor(&self.buf_u32).write_to(w)?;
+        // Transpose the doc-major entries into successor-major ones
+        self.buf_entries_transposed.clear();
+        self.buf_entries_transposed.extend(
+            self.buf_entries
+                .iter()
+                .map(|&(doc, successor)| (successor, doc)),
+        );
+        self.buf_entries_transposed.sort();
+
+        let doc_major = MatrixCompressor {
+            rows: docs.len(),
+            entries: &self.buf_entries,
+        };
+        let successor_major = MatrixCompressor {
+            rows: unique_successors.len(),
+            entries: &self.buf_entries_transposed,
+        };
+        let (matrix, other) = match self.matrix_layout {
+            MatrixLayout::DocMajor => (doc_major, successor_major),
+            MatrixLayout::SuccessorMajor => (successor_major, doc_major),
+        };
+        let (rows_size, columns_size) = matrix.write_to(w)?;
+        let (other_rows_size, other_columns_size) = other.write_to(&mut std::io::sink())?;
 
-        Ok(SequenceStats {
-            count: self.buf_u32.len(),
-            bytes: compressed_size,
-        })
+        Ok((
+            SequenceStats {
+                count: matrix.entries.len(),
+                bytes: rows_size + columns_size,
+            },
+            rows_size,
+            other_rows_size + other_columns_size,
+        ))
     }
 
     fn build_unique_docs<W: Write>(
@@ -199,7 +656,8 @@ impl IndexBuilder {
 
         let (unique_successors, unique_successors_stats) =
             self.build_unique_successors(&mut buf, &docs)?;
-        let successors_stats = self.build_successors(&mut buf, &unique_successors, &docs)?;
+        let (successors_stats, matrix_rows_bytes, other_layout_successors_bytes) =
+            self.build_successors(&mut buf, &unique_successors, &docs)?;
         let unique_docs_stats = self.build_unique_docs(&mut buf, &docs)?;
 
         let header = PostingHeader {
@@ -207,7 +665,8 @@ impl IndexBuilder {
             successors_count: unique_successors_stats.count.try_into()?,
             successors_bytes: unique_successors_stats.bytes.try_into()?,
             matrix_count: successors_stats.count.try_into()?,
-            matrix_bytes: successors_stats.bytes.try_into()?,
+            matrix_rows_bytes: matrix_rows_bytes.try_into()?,
+            matrix_bytes: (successors_stats.bytes - matrix_rows_bytes).try_into()?,
             docs_count: unique_docs_stats.count.try_into()?,
             docs_bytes: unique_docs_stats.bytes.try_into()?,
         };
@@ -219,60 +678,40 @@ impl IndexBuilder {
             header_bytes,
             unique_successors: unique_successors_stats,
             successors: successors_stats,
+            other_layout_successors_bytes,
             unique_docs: unique_docs_stats,
         })
     }
+}
 
-    pub fn build<W: Write>(mut self, w: &mut W) -> Result<IndexStats> {
-        let extract_stats = ExtractStats {
-            num_docs: self.num_docs,
-            doc_bytes: self.total_doc_bytes,
-            unique_trigrams: self.combined.len(),
-            extract_time: self.extract_duration,
-        };
-
-        let build_start = Instant::now();
-        let mut build_stats = BuildStats::default();
-        let mut posting_ends: Vec<(Trigram, u64)> = Vec::new();
-        let mut postings_len: u64 = 0;
-
-        for (trigram, docs) in std::mem::take(&mut self.combined).into_iter() {
-            let posting_stats = self.build_posting(w, trigram, &docs)?;
-            build_stats.add_posting(&posting_stats);
-            postings_len += posting_stats.total_bytes() as u64;
-            posting_ends.push((trigram, postings_len));
-        }
-
-        let mut unique_trigrams_len = 0;
-        for (trigram, _) in posting_ends.iter() {
-            unique_trigrams_len += w.write(&<[u8; 3]>::from(*trigram))?;
-        }
-
-        let mut offsets_len = 0;
-        for (_, offset) in posting_ends.iter() {
-            w.write_u64::<LittleEndian>(*offset)?;
-            offsets_len += 4;
-        }
-
-        let header = IndexHeader {
-            num_docs: self.num_docs as u32,
-            trigram_postings: Section::new(0, postings_len),
-            unique_trigrams: Section::new(postings_len, unique_trigrams_len as u64),
-            trigram_posting_ends: Section::new(
-                postings_len + unique_trigrams_len as u64,
-                offsets_len,
-            ),
-        };
-
-        header.write_to(w)?;
-
-        build_stats.posting_offsets_bytes = offsets_len as usize;
-        build_stats.build_time = build_start.elapsed();
-
-        Ok(IndexStats {
-            extract: extract_stats,
-            build: build_stats,
-            total_time: self.creation_time.elapsed(),
-        })
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_trigrams() {
+        let successors = |s: &[&[u8; 3]]| s.iter().map(|t| Trigram(**t)).collect::<FxHashSet<_>>();
+
+        // Each trigram is followed by the trigram three bytes later, padded past the end of the doc
+        let trigrams = IndexBuilder::extract_trigrams(b"the end");
+        assert_eq!(trigrams[&Trigram(*b"the")], successors(&[b" en"]));
+        assert_eq!(trigrams[&Trigram(*b"e e")], successors(&[b"nd\xFF"]));
+        assert_eq!(trigrams[&Trigram(*b"end")], successors(&[b"\xFF\xFF\xFF"]));
+
+        // The last two bytes of the doc are partial trigrams without successors
+        assert!(trigrams[&Trigram(*b"nd\xFF")].is_empty());
+        assert!(trigrams[&Trigram(*b"d\xFF\xFF")].is_empty());
+        assert_eq!(trigrams.len(), 7);
+
+        let trigrams = IndexBuilder::extract_trigrams(b"abcd");
+        assert_eq!(trigrams[&Trigram(*b"abc")], successors(&[b"d\xFF\xFF"]));
+        assert_eq!(trigrams[&Trigram(*b"bcd")], successors(&[b"\xFF\xFF\xFF"]));
+        assert_eq!(trigrams.len(), 4);
+
+        let trigrams = IndexBuilder::extract_trigrams(b"x");
+        assert_eq!(
+            trigrams.keys().collect::<Vec<_>>(),
+            [&Trigram(*b"x\xFF\xFF")]
+        );
     }
 }
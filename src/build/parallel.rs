@@ -0,0 +1,218 @@
+use std::io::Write;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+
+use super::stats::IndexStats;
+use super::IndexBuilder;
+use crate::DocID;
+
+// The number of docs per worker that can be waiting to have their trigrams extracted
+const QUEUED_DOCS_PER_WORKER: usize = 16;
+
+// Builds an index with trigrams extracted on a pool of worker threads, one per thread configured
+// with `IndexBuilder::threads`. Docs are added through `DocAdder` handles, which can be used from
+// any thread. Doc IDs are assigned in the order that docs are added, so the index is identical to
+// one built by adding the same docs to the `IndexBuilder` in that order.
+pub struct ParallelIndexBuilder {
+    builder: Arc<Mutex<IndexBuilder>>,
+    sender: SyncSender<(DocID, Vec<u8>)>,
+    workers: Vec<JoinHandle<Result<()>>>,
+    store_content: bool,
+
+    // Held by every `DocAdder`, so that they can be counted whether or not the workers are still
+    // running
+    adders: Arc<()>,
+}
+
+impl ParallelIndexBuilder {
+    pub fn new(builder: IndexBuilder) -> Self {
+        let threads = builder.threads;
+        let store_content = builder.store_content;
+        let builder = Arc::new(Mutex::new(builder));
+        let (sender, receiver) = sync_channel(threads * QUEUED_DOCS_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads)
+            .map(|_| {
+                let builder = builder.clone();
+                let receiver = receiver.clone();
+                std::thread::spawn(move || Self::extract(&builder, &receiver))
+            })
+            .collect();
+
+        Self {
+            builder,
+            sender,
+            workers,
+            store_content,
+            adders: Arc::new(()),
+        }
+    }
+
+    // Returns a handle for adding docs to the index
+    pub fn handle(&self) -> DocAdder {
+        DocAdder {
+            builder: self.builder.clone(),
+            sender: self.sender.clone(),
+            store_content: self.store_content,
+            _token: self.adders.clone(),
+        }
+    }
+
+    // Waits for the trigrams of every added doc to be extracted, then builds the index. Every
+    // `DocAdder` must have been dropped.
+    pub fn build<W: Write>(self, w: &mut W) -> Result<IndexStats> {
+        if Arc::strong_count(&self.adders) > 1 {
+            return Err(anyhow!("cannot build while docs can still be added"));
+        }
+
+        drop(self.sender);
+        for worker in self.workers {
+            worker
+                .join()
+                .map_err(|_| anyhow!("trigram extraction thread panicked"))??;
+        }
+
+        let builder = Arc::try_unwrap(self.builder)
+            .map_err(|_| anyhow!("cannot build while docs can still be added"))?
+            .into_inner()
+            .map_err(|_| anyhow!("trigram extraction thread panicked"))?;
+        builder.build(w)
+    }
+
+    // Extracts the trigrams of queued docs until every sender has been dropped
+    fn extract(
+        builder: &Mutex<IndexBuilder>,
+        receiver: &Mutex<Receiver<(DocID, Vec<u8>)>>,
+    ) -> Result<()> {
+        loop {
+            let (doc_id, content) = match lock(receiver)?.recv() {
+                Ok(doc) => doc,
+                Err(_) => return Ok(()),
+            };
+
+            let start = Instant::now();
+            let trigrams = IndexBuilder::extract_trigrams(&content);
+            let extract_duration = start.elapsed();
+
+            let mut builder = lock(builder)?;
+            builder.add_trigrams(doc_id, trigrams)?;
+            builder.extract_duration += extract_duration;
+        }
+    }
+}
+
+// Locks a mutex shared with the workers, which is only poisoned if one of them panicked
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| anyhow!("trigram extraction thread panicked"))
+}
+
+// A handle for adding docs to a `ParallelIndexBuilder`
+#[derive(Clone)]
+pub struct DocAdder {
+    builder: Arc<Mutex<IndexBuilder>>,
+    sender: SyncSender<(DocID, Vec<u8>)>,
+    store_content: bool,
+    _token: Arc<()>,
+}
+
+impl DocAdder {
+    // Adds a doc to the index, like `IndexBuilder::add_doc`
+    pub fn add_doc(&self, name: &str, content: &[u8]) -> Result<()> {
+        self.add_doc_with_metadata(name, &[], content)
+    }
+
+    // Adds a doc to the index along with key/value metadata, like
+    // `IndexBuilder::add_doc_with_metadata`. The doc ID is assigned immediately, and its trigrams
+    // are extracted by a worker.
+    pub fn add_doc_with_metadata(
+        &self,
+        name: &str,
+        metadata: &[(&str, &str)],
+        content: &[u8],
+    ) -> Result<()> {
+        let compressed_content = self
+            .store_content
+            .then(|| lz4_flex::compress_prepend_size(content));
+        let doc_id =
+            lock(&self.builder)?.register_doc(name, metadata, content.len(), compressed_content);
+        self.sender
+            .send((doc_id, content.to_vec()))
+            .map_err(|_| anyhow!("trigram extraction stopped after an error"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parallel_build() {
+        let docs: Vec<String> = (0..200)
+            .map(|i| format!("key{:03}; value{}", i % 70, i))
+            .collect();
+
+        let mut builder = IndexBuilder::new().store_content(true);
+        for (i, doc) in docs.iter().enumerate() {
+            builder
+                .add_doc_with_metadata(&format!("doc{}.txt", i), &[("n", "v")], doc.as_bytes())
+                .unwrap();
+        }
+        let mut expected = Vec::new();
+        builder.build(&mut expected).unwrap();
+
+        let builder = IndexBuilder::new()
+            .store_content(true)
+            .memory_budget(10_000)
+            .threads(4);
+        let builder = ParallelIndexBuilder::new(builder);
+        let adder = builder.handle();
+        for (i, doc) in docs.iter().enumerate() {
+            adder
+                .add_doc_with_metadata(&format!("doc{}.txt", i), &[("n", "v")], doc.as_bytes())
+                .unwrap();
+        }
+        drop(adder);
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        assert_eq!(output, expected);
+    }
+
+    // Builds with a spill dir that doesn't exist, so every worker fails on its first doc
+    fn failing_builder() -> ParallelIndexBuilder {
+        let dir = std::env::temp_dir().join(format!("trident-test-{:016x}", rand::random::<u64>()));
+        let builder = IndexBuilder::new()
+            .memory_budget(1)
+            .spill_dir(dir)
+            .threads(2);
+        ParallelIndexBuilder::new(builder)
+    }
+
+    #[test]
+    fn test_worker_error() {
+        // A live adder is rejected even though the workers have stopped
+        let builder = failing_builder();
+        let adder = builder.handle();
+        while adder.add_doc("doc.txt", b"some content").is_ok() {}
+        let err = builder.build(&mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot build while docs can still be added"
+        );
+        drop(adder);
+
+        let builder = failing_builder();
+        let adder = builder.handle();
+        adder.add_doc("doc.txt", b"some content").unwrap();
+        drop(adder);
+        let err = builder.build(&mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("failed to create spill file"));
+    }
+}
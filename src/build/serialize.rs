@@ -1,7 +1,7 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ops::Range;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bitpacking::{BitPacker, BitPacker4x};
 use integer_encoding::{VarIntReader, VarIntWriter};
 
@@ -35,16 +35,33 @@ impl StreamWriter for U32Compressor<'_> {
     }
 }
 
+// Compresses a sorted sequence of u32s as deltas. If there is at least one full bitpacked block,
+// the blocks are preceded by a skip table with the delta of the last value of each block and
+// the size of the block, which lets `U32DeltaDecompressor` skip blocks without decoding them.
 pub struct U32DeltaCompressor<'a>(pub &'a [u32]);
 
 impl StreamWriter for U32DeltaCompressor<'_> {
     fn write_to<W: Write>(&self, w: &mut W) -> Result<usize> {
         assert!(self.0.is_sorted());
         let mut size = 0;
+        let bp = BitPacker4x::new();
+
+        {
+            let mut skips = Vec::new();
+            let mut last = 0;
+            for chunk in self.0.chunks_exact(BitPacker4x::BLOCK_LEN) {
+                let num_bits = bp.num_bits_sorted(last, chunk);
+                skips.write_varint(*chunk.last().unwrap() - last)?;
+                skips.write_varint(1 + num_bits as usize * BitPacker4x::BLOCK_LEN / 8)?;
+                last = *chunk.last().unwrap();
+            }
+            w.write_all(&skips)?;
+            size += skips.len();
+        }
+
         let mut chunks = self.0.chunks_exact(BitPacker4x::BLOCK_LEN);
         let mut last = 0;
         {
-            let bp = BitPacker4x::new();
             let mut buf = [0u8; 4 * BitPacker4x::BLOCK_LEN];
             for chunk in chunks.by_ref() {
                 let num_bits = bp.num_bits_sorted(last, &chunk);
@@ -64,12 +81,26 @@ impl StreamWriter for U32DeltaCompressor<'_> {
     }
 }
 
+// Returns the error for a bitpacked block whose bit width could not have been written
+fn check_num_bits(num_bits: u8) -> io::Result<u8> {
+    match num_bits {
+        0..=32 => Ok(num_bits),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bitpacked block has {} bits per value", num_bits),
+        )),
+    }
+}
+
+// Decompresses a sequence written by `U32Compressor`. If the data cannot be read, the iterator
+// ends early and `take_error` returns the error.
 pub struct U32Decompressor<R: Read> {
     r: R,
     remaining: usize,
     chunk: [u32; BitPacker4x::BLOCK_LEN],
     chunk_range: Range<usize>,
     buf: [u8; BitPacker4x::BLOCK_LEN * 4],
+    error: Option<io::Error>,
 }
 
 impl<R: Read> Iterator for U32Decompressor<R> {
@@ -79,11 +110,36 @@ impl<R: Read> Iterator for U32Decompressor<R> {
         match self.chunk_range.next() {
             Some(n) => Some(self.chunk[n]),
             None => {
-                self.populate_next_chunk();
+                if let Err(err) = self.populate_next_chunk() {
+                    self.fail(err);
+                }
                 Some(self.chunk[self.chunk_range.next()?])
             }
         }
     }
+
+    // Reads past whole blocks without decoding them
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        if n < self.chunk_range.len() {
+            self.chunk_range.start += n;
+            return self.next();
+        }
+
+        let mut n = n - self.chunk_range.len();
+        self.chunk_range.start = self.chunk_range.end;
+        while n >= BitPacker4x::BLOCK_LEN && self.remaining >= BitPacker4x::BLOCK_LEN {
+            if let Err(err) = self.skip_block() {
+                self.fail(err);
+                return None;
+            }
+            n -= BitPacker4x::BLOCK_LEN;
+        }
+
+        for _ in 0..n {
+            self.next()?;
+        }
+        self.next()
+    }
 }
 
 impl<R: Read> U32Decompressor<R> {
@@ -94,78 +150,190 @@ impl<R: Read> U32Decompressor<R> {
             chunk: [0u32; BitPacker4x::BLOCK_LEN],
             chunk_range: 0..0,
             buf: [0u8; BitPacker4x::BLOCK_LEN * 4],
+            error: None,
+        }
+    }
+
+    // Returns the error that ended the iterator early, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn fail(&mut self, err: io::Error) {
+        self.error = Some(err);
+        self.remaining = 0;
+        self.chunk_range = 0..0;
+    }
+
+    fn skip_block(&mut self) -> io::Result<()> {
+        let mut buf = [0; 1];
+        self.r.read_exact(&mut buf)?;
+        let num_bytes = check_num_bits(buf[0])? as u64 * BitPacker4x::BLOCK_LEN as u64 / 8;
+        let skipped = io::copy(&mut self.r.by_ref().take(num_bytes), &mut io::sink())?;
+        if skipped < num_bytes {
+            return Err(io::ErrorKind::UnexpectedEof.into());
         }
+        self.remaining -= BitPacker4x::BLOCK_LEN;
+        Ok(())
     }
 
-    fn populate_next_chunk(&mut self) {
+    fn populate_next_chunk(&mut self) -> io::Result<()> {
         if self.remaining >= BitPacker4x::BLOCK_LEN {
             let bp = BitPacker4x::new();
             let num_bits = {
                 let mut buf = [0; 1];
-                self.r.read_exact(&mut buf).unwrap();
-                buf[0]
+                self.r.read_exact(&mut buf)?;
+                check_num_bits(buf[0])?
             };
             let num_bytes = num_bits as usize * BitPacker4x::BLOCK_LEN / 8;
-            self.r.read_exact(&mut self.buf[..num_bytes]).unwrap();
+            self.r.read_exact(&mut self.buf[..num_bytes])?;
             let n = bp.decompress(&self.buf[..num_bytes], &mut self.chunk, num_bits);
             self.chunk_range = 0..BitPacker4x::BLOCK_LEN;
             assert!(n == num_bytes);
             self.remaining -= BitPacker4x::BLOCK_LEN;
         } else {
             for i in 0..self.remaining {
-                self.chunk[i] = self.r.read_varint().unwrap();
+                self.chunk[i] = self.r.read_varint()?;
             }
             self.chunk_range = 0..self.remaining;
             self.remaining = 0;
         }
+        Ok(())
     }
 }
 
+// Skipping fewer bytes than this reads through them rather than seeking, which would discard any
+// data buffered by the reader.
+const MIN_SEEK_BYTES: u64 = 4096;
+
+// The last value of a bitpacked block and the offset of the end of the block from the end of the
+// skip table
+#[derive(Debug, Clone, Copy)]
+struct BlockSkip {
+    last: u32,
+    end: u64,
+}
+
+// Decompresses a sequence written by `U32DeltaCompressor`. If the data cannot be read, the
+// iterator ends early and `take_error` returns the error.
 pub struct U32DeltaDecompressor<R: Read> {
     r: R,
+    count: usize,
     remaining: usize,
     chunk: [u32; BitPacker4x::BLOCK_LEN],
     chunk_range: Range<usize>,
     buf: [u8; BitPacker4x::BLOCK_LEN * 4],
+
+    // The skip table, the index of the next block to decode, and the number of bytes of blocks
+    // read so far
+    skips: Vec<BlockSkip>,
+    next_block: usize,
+    offset: u64,
+
+    error: Option<io::Error>,
 }
 
 // TODO this should implement ExactSizeIterator
-impl<R: Read> Iterator for U32DeltaDecompressor<R> {
+impl<R: Read + Seek> Iterator for U32DeltaDecompressor<R> {
     type Item = u32;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.chunk_range.next() {
             Some(n) => Some(self.chunk[n]),
             None => {
-                self.populate_next_chunk();
+                if let Err(err) = self.populate_next_chunk() {
+                    self.fail(err);
+                }
                 Some(self.chunk[self.chunk_range.next()?])
             }
         }
     }
+
+    // Skips whole blocks without decoding them
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        if n < self.chunk_range.len() {
+            self.chunk_range.start += n;
+            return self.next();
+        }
+
+        let mut n = n - self.chunk_range.len();
+        self.chunk_range.start = self.chunk_range.end;
+        let blocks = (n / BitPacker4x::BLOCK_LEN).min(self.skips.len() - self.next_block);
+        if blocks > 0 {
+            if let Err(err) = self.skip_to_block(self.next_block + blocks) {
+                self.fail(err);
+                return None;
+            }
+            n -= blocks * BitPacker4x::BLOCK_LEN;
+        }
+
+        for _ in 0..n {
+            self.next()?;
+        }
+        self.next()
+    }
 }
 
 impl<R: Read> U32DeltaDecompressor<R> {
-    pub fn new(r: R, count: usize) -> Self {
-        Self {
+    pub fn new(mut r: R, count: usize) -> Self {
+        let blocks = count / BitPacker4x::BLOCK_LEN;
+        let mut skips = Vec::new();
+        let mut read_skips = || -> io::Result<()> {
+            let (mut last, mut end) = (0u32, 0u64);
+            for _ in 0..blocks {
+                last = last.wrapping_add(r.read_varint::<u32>()?);
+                end = end.wrapping_add(r.read_varint::<u64>()?);
+                skips.push(BlockSkip { last, end });
+            }
+            Ok(())
+        };
+        let error = read_skips().err();
+
+        let mut decompressor = Self {
+            count,
             remaining: count,
             r,
             chunk: [0u32; BitPacker4x::BLOCK_LEN],
             chunk_range: 0..0,
             buf: [0u8; BitPacker4x::BLOCK_LEN * 4],
+            skips,
+            next_block: 0,
+            offset: 0,
+            error: None,
+        };
+        if let Some(err) = error {
+            decompressor.fail(err);
         }
+        decompressor
+    }
+
+    // Returns the error that ended the iterator early, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
     }
 
-    fn populate_next_chunk(&mut self) {
+    fn fail(&mut self, err: io::Error) {
+        self.error = Some(err);
+        self.remaining = 0;
+        self.chunk_range.start = self.chunk_range.end;
+        self.next_block = self.skips.len();
+    }
+
+    // Returns the number of values that have been yielded or skipped
+    pub fn yielded(&self) -> usize {
+        self.count - self.remaining - self.chunk_range.len()
+    }
+
+    fn populate_next_chunk(&mut self) -> io::Result<()> {
         if self.remaining >= BitPacker4x::BLOCK_LEN {
             let bp = BitPacker4x::new();
             let num_bits = {
                 let mut buf = [0; 1];
-                self.r.read_exact(&mut buf).unwrap();
-                assert!(buf[0] < 32);
-                buf[0]
+                self.r.read_exact(&mut buf)?;
+                check_num_bits(buf[0])?
             };
             let num_bytes = num_bits as usize * BitPacker4x::BLOCK_LEN / 8;
-            self.r.read_exact(&mut self.buf[..num_bytes]).unwrap();
+            self.r.read_exact(&mut self.buf[..num_bytes])?;
             let n = bp.decompress_sorted(
                 self.chunk[BitPacker4x::BLOCK_LEN - 1],
                 &self.buf[..num_bytes],
@@ -175,15 +343,286 @@ impl<R: Read> U32DeltaDecompressor<R> {
             self.chunk_range = 0..BitPacker4x::BLOCK_LEN;
             assert!(n == num_bytes);
             self.remaining -= BitPacker4x::BLOCK_LEN;
+            self.next_block += 1;
+            self.offset += 1 + num_bytes as u64;
         } else {
             let mut last = self.chunk[BitPacker4x::BLOCK_LEN - 1];
             for i in 0..self.remaining {
-                self.chunk[i] = self.r.read_varint::<u32>().unwrap() + last;
+                self.chunk[i] = self.r.read_varint::<u32>()?.wrapping_add(last);
                 last = self.chunk[i];
             }
             self.chunk_range = 0..self.remaining;
             self.remaining = 0;
         }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> U32DeltaDecompressor<R> {
+    // Advances to the first remaining value that is at least `target` and returns it. Blocks
+    // whose last value is less than `target` are skipped without being decoded.
+    pub fn advance_to(&mut self, target: u32) -> Option<u32> {
+        let chunk_last = match self.chunk_range.end {
+            0 => None,
+            end => Some(self.chunk[end - 1]),
+        };
+        if self.chunk_range.is_empty() || chunk_last < Some(target) {
+            self.chunk_range.start = self.chunk_range.end;
+            let blocks = self.skips[self.next_block..].partition_point(|s| s.last < target);
+            if blocks > 0 {
+                if let Err(err) = self.skip_to_block(self.next_block + blocks) {
+                    self.fail(err);
+                    return None;
+                }
+            }
+        }
+
+        loop {
+            let value = self.next()?;
+            if value >= target {
+                return Some(value);
+            }
+        }
+    }
+
+    // Moves the reader to the start of a later block, discarding the current chunk
+    fn skip_to_block(&mut self, block: usize) -> io::Result<()> {
+        assert!(block > self.next_block && block <= self.skips.len());
+        let skipped = self.skips[block - 1];
+        let distance = skipped
+            .end
+            .checked_sub(self.offset)
+            .filter(|&distance| distance <= i64::MAX as u64)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "block skip table is not sorted")
+            })?;
+        if distance < MIN_SEEK_BYTES {
+            io::copy(&mut self.r.by_ref().take(distance), &mut io::sink())?;
+        } else {
+            self.r.seek(SeekFrom::Current(distance as i64))?;
+        }
+
+        self.remaining -= (block - self.next_block) * BitPacker4x::BLOCK_LEN;
+        self.chunk[BitPacker4x::BLOCK_LEN - 1] = skipped.last;
+        self.chunk_range = 0..0;
+        self.next_block = block;
+        self.offset = skipped.end;
+        Ok(())
+    }
+}
+
+// Compresses a sparse matrix given as sorted (row, column) entries. The matrix is written as two
+// sequences: the end of each row in the list of entries, then the column of each entry as the gap
+// from the previous column in its row. No coordinate depends on the size of the matrix, so any
+// matrix whose entries can be counted in a u32 can be written.
+pub struct MatrixCompressor<'a> {
+    pub rows: usize,
+    pub entries: &'a [(u32, u32)],
+}
+
+impl MatrixCompressor<'_> {
+    // Writes the row ends and the columns, returning the size of each
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(usize, usize)> {
+        assert!(self.entries.is_sorted());
+        u32::try_from(self.entries.len()).context("too many entries in matrix")?;
+
+        let mut row_ends = vec![0u32; self.rows];
+        let mut gaps = Vec::with_capacity(self.entries.len());
+        let mut last = None;
+        for &(row, column) in self.entries {
+            row_ends[row as usize] += 1;
+            gaps.push(match last {
+                Some((last_row, last_column)) if last_row == row => column - last_column,
+                _ => column,
+            });
+            last = Some((row, column));
+        }
+        for i in 1..row_ends.len() {
+            row_ends[i] += row_ends[i - 1];
+        }
+
+        let rows_size = U32DeltaCompressor(&row_ends).write_to(w)?;
+        let columns_size = U32Compressor(&gaps).write_to(w)?;
+        Ok((rows_size, columns_size))
+    }
+}
+
+// Iterates over the (row, column) entries of a matrix written by `MatrixCompressor`. If the data
+// cannot be read, the iterator ends early and `take_error` returns the error.
+pub struct MatrixDecompressor<R: Read> {
+    row_ends: U32DeltaDecompressor<R>,
+    columns: U32Decompressor<R>,
+
+    // The index of the row after the current one, the index of the next entry, the index of the
+    // entry after the current row, and the last column read
+    next_row: u32,
+    entry: u32,
+    row_end: u32,
+    column: u32,
+}
+
+impl<R: Read> MatrixDecompressor<R> {
+    pub fn new(rows_r: R, rows: usize, columns_r: R, count: usize) -> Self {
+        Self {
+            row_ends: U32DeltaDecompressor::new(rows_r, rows),
+            columns: U32Decompressor::new(columns_r, count),
+            next_row: 0,
+            entry: 0,
+            row_end: 0,
+            column: 0,
+        }
+    }
+
+    // Returns the error that ended the iterator early, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.row_ends
+            .take_error()
+            .or_else(|| self.columns.take_error())
+    }
+}
+
+impl<R: Read + Seek> Iterator for MatrixDecompressor<R> {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let gap = self.columns.next()?;
+        if self.entry == self.row_end {
+            while self.entry == self.row_end {
+                self.row_end = self.row_ends.next()?;
+                self.next_row += 1;
+            }
+            self.column = gap;
+        } else {
+            self.column = self.column.wrapping_add(gap);
+        }
+        self.entry += 1;
+        Some((self.next_row - 1, self.column))
+    }
+}
+
+impl<R: Read + Seek> MatrixDecompressor<R> {
+    // Skips to the first entry of `row`, which must not be before the current row. Blocks of row
+    // ends and columns are skipped without being decoded.
+    pub fn skip_to_row(&mut self, row: u32) {
+        assert!(row >= self.next_row);
+        let start = match row - self.next_row {
+            0 => self.row_end,
+            n => match self.row_ends.nth(n as usize - 1) {
+                Some(end) => end,
+                None => {
+                    self.columns.by_ref().for_each(drop);
+                    return;
+                }
+            },
+        };
+
+        if start > self.entry {
+            self.columns.nth((start - self.entry - 1) as usize);
+        }
+        self.entry = start;
+        self.row_end = start;
+        self.next_row = row;
+    }
+}
+
+// The number of strings in each front-coded block. The first string of a block is stored in full
+// so that any block can be decoded on its own.
+pub const FRONT_CODED_BLOCK_LEN: usize = 16;
+
+// Incrementally front-codes a sequence of strings. Each string is written as the length of the
+// prefix it shares with the previous string in its block, followed by the length and bytes of the
+// rest of the string.
+#[derive(Default)]
+pub struct FrontCodedEncoder {
+    buf: Vec<u8>,
+    block_ends: Vec<u64>,
+    last: Vec<u8>,
+    count: usize,
+}
+
+impl FrontCodedEncoder {
+    pub fn push(&mut self, s: &[u8]) {
+        if self.count.is_multiple_of(FRONT_CODED_BLOCK_LEN) {
+            self.last.clear();
+        }
+
+        let shared = self
+            .last
+            .iter()
+            .zip(s.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        self.buf.write_varint(shared).unwrap();
+        self.buf.write_varint(s.len() - shared).unwrap();
+        self.buf.extend_from_slice(&s[shared..]);
+
+        self.last.clear();
+        self.last.extend_from_slice(s);
+        self.count += 1;
+        if self.count.is_multiple_of(FRONT_CODED_BLOCK_LEN) {
+            self.block_ends.push(self.buf.len() as u64);
+        }
+    }
+
+    // Returns the encoded strings and the end offset of each block in them
+    pub fn finish(mut self) -> (Vec<u8>, Vec<u64>) {
+        if !self.count.is_multiple_of(FRONT_CODED_BLOCK_LEN) {
+            self.block_ends.push(self.buf.len() as u64);
+        }
+        (self.buf, self.block_ends)
+    }
+}
+
+// Decodes the strings of a single front-coded block. The iterator ends after the first error.
+pub struct FrontCodedDecoder<R: Read> {
+    r: R,
+    remaining: usize,
+    last: Vec<u8>,
+}
+
+impl<R: Read> FrontCodedDecoder<R> {
+    pub fn new(r: R, count: usize) -> Self {
+        Self {
+            r,
+            remaining: count,
+            last: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for FrontCodedDecoder<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let result = self.read_next();
+        if result.is_err() {
+            self.remaining = 0;
+        }
+        Some(result)
+    }
+}
+
+impl<R: Read> FrontCodedDecoder<R> {
+    fn read_next(&mut self) -> io::Result<Vec<u8>> {
+        let shared: usize = self.r.read_varint()?;
+        let suffix_len: u64 = self.r.read_varint()?;
+        self.last.truncate(shared);
+        // The suffix is read through `take` so that a corrupt length cannot allocate more than
+        // the data that is left
+        let read = self
+            .r
+            .by_ref()
+            .take(suffix_len)
+            .read_to_end(&mut self.last)?;
+        if (read as u64) < suffix_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(self.last.clone())
     }
 }
 
@@ -214,4 +653,148 @@ mod test {
             input == output
         }
     }
+
+    quickcheck! {
+        fn delta_advance_to(input: Vec<u32>, targets: Vec<u32>) -> bool {
+            let mut input = input;
+            input.sort();
+            // Make sure there are multiple blocks to skip
+            let input = input.iter().flat_map(|&i| [i; 100]).collect::<Vec<u32>>();
+            let mut targets = targets;
+            targets.sort();
+
+            let mut buf = Vec::new();
+            U32DeltaCompressor(input.as_slice()).write_to(&mut buf).unwrap();
+            let mut decompressor = U32DeltaDecompressor::new(Cursor::new(buf), input.len());
+
+            // Each target is found at or after the previous one
+            let mut pos = 0;
+            for target in targets {
+                let expected = input[pos..].iter().position(|&i| i >= target).map(|p| pos + p);
+                let found = decompressor.advance_to(target);
+                if found != expected.map(|p| input[p]) {
+                    return false;
+                }
+                match expected {
+                    Some(p) if decompressor.yielded() == p + 1 => pos = p + 1,
+                    None => return true,
+                    _ => return false,
+                }
+            }
+            decompressor.collect::<Vec<u32>>() == input[pos..]
+        }
+    }
+
+    quickcheck! {
+        fn delta_nth(input: Vec<u32>, skips: Vec<u16>) -> bool {
+            let mut input = input;
+            input.sort();
+            let input = input.iter().flat_map(|&i| [i; 100]).collect::<Vec<u32>>();
+
+            let mut buf = Vec::new();
+            U32DeltaCompressor(input.as_slice()).write_to(&mut buf).unwrap();
+            let mut decompressor = U32DeltaDecompressor::new(Cursor::new(buf), input.len());
+            let mut expected = input.iter();
+            skips
+                .into_iter()
+                .all(|n| decompressor.nth(n as usize) == expected.nth(n as usize).copied())
+        }
+    }
+
+    quickcheck! {
+        fn matrix_roundtrip(entries: Vec<(u8, u32)>, skip_to: u8) -> bool {
+            let mut entries = entries.into_iter().map(|(r, c)| (r as u32, c)).collect::<Vec<_>>();
+            entries.sort();
+            entries.dedup();
+
+            let mut buf = Vec::new();
+            let (rows_size, _) = MatrixCompressor { rows: 256, entries: &entries }
+                .write_to(&mut buf)
+                .unwrap();
+            let decompressor = || MatrixDecompressor::new(
+                Cursor::new(&buf[..rows_size]),
+                256,
+                Cursor::new(&buf[rows_size..]),
+                entries.len(),
+            );
+
+            let mut skipped = decompressor();
+            skipped.skip_to_row(skip_to as u32);
+            decompressor().collect::<Vec<_>>() == entries
+                && skipped.collect::<Vec<_>>() == entries
+                    .iter()
+                    .copied()
+                    .filter(|&(r, _)| r >= skip_to as u32)
+                    .collect::<Vec<_>>()
+        }
+    }
+
+    #[test]
+    fn test_truncated_delta() {
+        let input = (0..1000).map(|i| i * 3).collect::<Vec<u32>>();
+        let mut buf = Vec::new();
+        U32DeltaCompressor(&input).write_to(&mut buf).unwrap();
+
+        // Cutting the data short ends the iterator early with an error
+        let mut decompressor =
+            U32DeltaDecompressor::new(Cursor::new(&buf[..buf.len() / 2]), input.len());
+        let output: Vec<u32> = decompressor.by_ref().collect();
+        assert!(output.len() < input.len());
+        assert_eq!(output, input[..output.len()]);
+        let err = decompressor.take_error().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Skipping blocks past the end of the data fails the same way
+        let mut decompressor =
+            U32DeltaDecompressor::new(Cursor::new(&buf[..buf.len() / 2]), input.len());
+        assert_eq!(decompressor.advance_to(2990), None);
+        assert!(decompressor.take_error().is_some());
+
+        let mut decompressor = U32DeltaDecompressor::new(Cursor::new(&buf[..4]), input.len());
+        assert_eq!(decompressor.next(), None);
+        assert!(decompressor.take_error().is_some());
+    }
+
+    #[test]
+    fn test_matrix_large_coordinates() {
+        // The product of the largest row and column overflows a u32
+        let entries = [(0, 7), (0, u32::MAX - 1), (99_999, 3), (99_999, u32::MAX)];
+        let mut buf = Vec::new();
+        let (rows_size, _) = MatrixCompressor {
+            rows: 100_000,
+            entries: &entries,
+        }
+        .write_to(&mut buf)
+        .unwrap();
+
+        let mut decompressor = MatrixDecompressor::new(
+            Cursor::new(&buf[..rows_size]),
+            100_000,
+            Cursor::new(&buf[rows_size..]),
+            entries.len(),
+        );
+        assert_eq!(decompressor.next(), Some(entries[0]));
+        decompressor.skip_to_row(50_000);
+        assert_eq!(decompressor.collect::<Vec<_>>(), &entries[2..]);
+    }
+
+    quickcheck! {
+        fn front_coded_roundtrip(input: Vec<Vec<u8>>) -> bool {
+            let mut encoder = FrontCodedEncoder::default();
+            for s in input.iter() {
+                encoder.push(s);
+            }
+            let (buf, block_ends) = encoder.finish();
+
+            let mut output = Vec::new();
+            let mut start = 0;
+            for (i, end) in block_ends.into_iter().enumerate() {
+                let count = FRONT_CODED_BLOCK_LEN.min(input.len() - i * FRONT_CODED_BLOCK_LEN);
+                let block = Cursor::new(&buf[start as usize..end as usize]);
+                output.extend(FrontCodedDecoder::new(block, count).map(Result::unwrap));
+                start = end;
+            }
+            input == output
+        }
+    }
 }
@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::parallel::{DocAdder, ParallelIndexBuilder};
+use super::stats::IndexStats;
+use super::IndexBuilder;
+use crate::index::sharded::write_manifest;
+
+// Builds an index as a series of shards, starting a new shard whenever the current one would
+// exceed the configured number of docs or bytes of content. Each shard is written next to the
+// manifest as soon as it is full, with the manifest's file name followed by the shard number, and
+// the manifest is written once every shard is. The result can be opened with
+// `ShardedIndex::open`.
+pub struct ShardedIndexBuilder {
+    manifest_path: PathBuf,
+
+    // Creates the builder for each shard
+    new_builder: Box<dyn Fn() -> IndexBuilder>,
+    max_shard_docs: Option<usize>,
+    max_shard_bytes: Option<usize>,
+
+    current: Option<(ParallelIndexBuilder, DocAdder)>,
+    current_docs: usize,
+    current_bytes: usize,
+
+    shard_names: Vec<String>,
+    stats: Vec<IndexStats>,
+}
+
+impl ShardedIndexBuilder {
+    pub fn new(manifest_path: &Path, new_builder: impl Fn() -> IndexBuilder + 'static) -> Self {
+        Self {
+            manifest_path: manifest_path.to_path_buf(),
+            new_builder: Box::new(new_builder),
+            max_shard_docs: None,
+            max_shard_bytes: None,
+            current: None,
+            current_docs: 0,
+            current_bytes: 0,
+            shard_names: Vec::new(),
+            stats: Vec::new(),
+        }
+    }
+
+    pub fn max_shard_docs(mut self, docs: usize) -> Self {
+        self.max_shard_docs = Some(docs.max(1));
+        self
+    }
+
+    // Sets the maximum total size of the content of the docs in a shard. A doc that is larger than
+    // this gets a shard of its own.
+    pub fn max_shard_bytes(mut self, bytes: usize) -> Self {
+        self.max_shard_bytes = Some(bytes);
+        self
+    }
+
+    pub fn add_doc(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        self.add_doc_with_metadata(name, &[], content)
+    }
+
+    pub fn add_doc_with_metadata(
+        &mut self,
+        name: &str,
+        metadata: &[(&str, &str)],
+        content: &[u8],
+    ) -> Result<()> {
+        let full_docs = self
+            .max_shard_docs
+            .is_some_and(|max| self.current_docs >= max);
+        let full_bytes = self
+            .max_shard_bytes
+            .is_some_and(|max| self.current_bytes + content.len() > max);
+        let full = self.current_docs > 0 && (full_docs || full_bytes);
+        if full {
+            self.finish_shard()?;
+        }
+
+        let (_, adder) = self.current.get_or_insert_with(|| {
+            let builder = ParallelIndexBuilder::new((self.new_builder)());
+            let adder = builder.handle();
+            (builder, adder)
+        });
+        adder.add_doc_with_metadata(name, metadata, content)?;
+        self.current_docs += 1;
+        self.current_bytes += content.len();
+        Ok(())
+    }
+
+    // Writes the last shard and the manifest. Returns the stats of each shard.
+    pub fn build(mut self) -> Result<Vec<IndexStats>> {
+        // An index without docs still gets a shard, so that it can be opened
+        if self.current.is_some() || self.shard_names.is_empty() {
+            self.finish_shard()?;
+        }
+        write_manifest(&self.manifest_path, &self.shard_names)?;
+        Ok(self.stats)
+    }
+
+    fn finish_shard(&mut self) -> Result<()> {
+        let builder = match self.current.take() {
+            Some((builder, adder)) => {
+                drop(adder);
+                builder
+            }
+            None => ParallelIndexBuilder::new((self.new_builder)()),
+        };
+
+        let mut name = self
+            .manifest_path
+            .file_name()
+            .context("manifest path has no file name")?
+            .to_os_string();
+        name.push(format!(".{}", self.shard_names.len()));
+        let name = name
+            .into_string()
+            .map_err(|name| anyhow!("shard name {:?} is not valid UTF-8", name))?;
+        let path = self.manifest_path.with_file_name(&name);
+
+        let mut w = BufWriter::new(File::create(&path)?);
+        self.stats.push(builder.build(&mut w)?);
+        w.into_inner()?.sync_all()?;
+        self.shard_names.push(name);
+        self.current_docs = 0;
+        self.current_bytes = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index::sharded::{read_manifest, ShardedIndex};
+    use crate::query::Query;
+
+    #[test]
+    fn test_sharded_build() {
+        let dir = std::env::temp_dir().join(format!("trident-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let manifest_path = dir.join("index");
+
+        let docs: Vec<String> = (0..50)
+            .map(|i| format!("fn item{}() {{ {} }}", i, "x".repeat(i % 7)))
+            .collect();
+        let mut builder =
+            ShardedIndexBuilder::new(&manifest_path, || IndexBuilder::new().store_content(true))
+                .max_shard_docs(12)
+                .max_shard_bytes(200);
+        for (i, doc) in docs.iter().enumerate() {
+            builder
+                .add_doc(&format!("doc{}.rs", i), doc.as_bytes())
+                .unwrap();
+        }
+        let stats = builder.build().unwrap();
+
+        let paths = read_manifest(&manifest_path).unwrap();
+        assert_eq!(paths.len(), stats.len());
+        assert!(paths.len() > 50 / 12);
+        assert_eq!(paths[1], dir.join("index.1"));
+        let num_docs: Vec<usize> = stats.iter().map(|s| s.extract.num_docs).collect();
+        assert!(num_docs.iter().all(|&n| n <= 12));
+        assert!(stats.iter().all(|s| s.extract.doc_bytes <= 200));
+
+        let index = ShardedIndex::open(&manifest_path).unwrap();
+        assert_eq!(index.num_docs(), 50);
+        let query = Query::parse("item1").unwrap();
+        let expected: Vec<usize> = (0..50).filter(|i| docs[*i].contains("item1")).collect();
+        let found = index.search(&query).unwrap();
+        assert_eq!(
+            found.iter().map(|&d| d as usize).collect::<Vec<_>>(),
+            expected
+        );
+        let candidates: Vec<_> = index.candidates(&query).collect();
+        assert!(found.iter().all(|doc_id| candidates.contains(doc_id)));
+        for doc_match in index.search_matches(&query, 0).unwrap() {
+            assert_eq!(
+                index.content(doc_match.doc_id).unwrap(),
+                docs[doc_match.doc_id as usize].as_bytes()
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sharded_build_empty() {
+        let dir = std::env::temp_dir().join(format!("trident-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let manifest_path = dir.join("index");
+
+        ShardedIndexBuilder::new(&manifest_path, IndexBuilder::new)
+            .build()
+            .unwrap();
+        let index = ShardedIndex::open(&manifest_path).unwrap();
+        assert_eq!(index.shards().len(), 1);
+        assert_eq!(index.num_docs(), 0);
+        assert!(index
+            .search(&Query::parse("abc").unwrap())
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use integer_encoding::{VarIntReader, VarIntWriter};
+use rustc_hash::FxHashSet;
+
+use crate::{DocID, Trigram};
+
+// The docs that contain a trigram, along with the successors of the trigram in each doc
+pub type Postings = Vec<(DocID, FxHashSet<Trigram>)>;
+
+// A sorted partial run of postings that was spilled to a temporary file. The file is removed when
+// the run is dropped.
+pub struct Run {
+    path: PathBuf,
+}
+
+impl Run {
+    // Writes the postings to a new file in `dir`. The file starts with the number of trigrams,
+    // followed by each trigram and its doc count, then each doc ID with its successors.
+    pub fn write(dir: &Path, postings: &BTreeMap<Trigram, Postings>) -> Result<Self> {
+        let path = dir.join(format!(
+            "trident-{}-{:016x}.run",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("failed to create spill file {:?}", path))?;
+        let run = Run { path };
+
+        let mut w = BufWriter::new(file);
+        w.write_varint(postings.len())?;
+        for (trigram, docs) in postings.iter() {
+            w.write_all(&<[u8; 3]>::from(*trigram))?;
+            w.write_varint(docs.len())?;
+            for (doc_id, successors) in docs.iter() {
+                w.write_varint(*doc_id)?;
+                w.write_varint(successors.len())?;
+                for successor in successors.iter() {
+                    w.write_all(&<[u8; 3]>::from(*successor))?;
+                }
+            }
+        }
+        w.flush()?;
+        Ok(run)
+    }
+
+    // Returns an iterator over the postings of the run in trigram order
+    pub fn read(&self) -> Result<RunReader> {
+        let mut r = BufReader::new(File::open(&self.path)?);
+        let remaining = r.read_varint()?;
+        Ok(RunReader { r, remaining })
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub struct RunReader {
+    r: BufReader<File>,
+    remaining: usize,
+}
+
+impl RunReader {
+    fn read_trigram(&mut self) -> io::Result<Trigram> {
+        let mut buf = [0u8; 3];
+        self.r.read_exact(&mut buf)?;
+        Ok(Trigram::from(buf))
+    }
+
+    fn read_posting(&mut self) -> io::Result<(Trigram, Postings)> {
+        let trigram = self.read_trigram()?;
+        let docs_count: usize = self.r.read_varint()?;
+        let mut docs = Vec::with_capacity(docs_count);
+        for _ in 0..docs_count {
+            let doc_id = self.r.read_varint()?;
+            let successors_count: usize = self.r.read_varint()?;
+            let successors = (0..successors_count)
+                .map(|_| self.read_trigram())
+                .collect::<io::Result<_>>()?;
+            docs.push((doc_id, successors));
+        }
+        Ok((trigram, docs))
+    }
+}
+
+impl Iterator for RunReader {
+    type Item = io::Result<(Trigram, Postings)>;
+
+    // Yields an error and ends the run if the file can't be read, such as if it was truncated
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let posting = self.read_posting();
+        if posting.is_err() {
+            self.remaining = 0;
+        }
+        Some(posting)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_truncated_run() {
+        let mut postings = BTreeMap::new();
+        for i in 0..10u8 {
+            let successors = FxHashSet::from_iter([Trigram([i, i, i])]);
+            postings.insert(Trigram([b'a', b'b', i]), vec![(i as DocID, successors)]);
+        }
+        let run = Run::write(&std::env::temp_dir(), &postings).unwrap();
+        let read = run.read().unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(read, postings.clone().into_iter().collect::<Vec<_>>());
+
+        let len = std::fs::metadata(&run.path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&run.path).unwrap();
+        file.set_len(len - 5).unwrap();
+        let read = run.read().unwrap().collect::<Vec<_>>();
+        assert_eq!(read.len(), 10);
+        assert!(read[..9].iter().all(Result::is_ok));
+        assert!(read[9].is_err());
+    }
+}
@@ -22,6 +22,10 @@ pub struct ExtractStats {
     // The number of unique trigrams in the indexed documents
     pub unique_trigrams: usize,
 
+    // The number of sorted runs of postings that were spilled to disk to stay within the memory
+    // budget
+    pub spilled_runs: usize,
+
     // The total time it took to extract trigrams from docs
     pub extract_time: Duration,
 }
@@ -39,6 +43,18 @@ pub struct BuildStats {
 
     pub posting_offsets_bytes: usize,
 
+    // The size of the trigram presence table, if the index has one
+    pub presence_table_bytes: usize,
+
+    // The size of the stored doc contents, including their offsets
+    pub content_bytes: usize,
+
+    // The size of the doc names, including their block offsets
+    pub doc_name_bytes: usize,
+
+    // The size of the doc metadata
+    pub metadata_bytes: usize,
+
     // The total time it took to write the index to disk
     pub build_time: Duration,
 }
@@ -50,6 +66,10 @@ impl Default for BuildStats {
             postings_max: TrigramPostingStats::default(),
             postings_sum: TrigramPostingStats::default(),
             posting_offsets_bytes: 0,
+            presence_table_bytes: 0,
+            content_bytes: 0,
+            doc_name_bytes: 0,
+            metadata_bytes: 0,
             build_time: Duration::default(),
         }
     }
@@ -63,7 +83,12 @@ impl BuildStats {
     }
 
     pub fn total_size_bytes(&self) -> usize {
-        self.postings_sum.total_bytes() + self.posting_offsets_bytes
+        self.postings_sum.total_bytes()
+            + self.posting_offsets_bytes
+            + self.presence_table_bytes
+            + self.content_bytes
+            + self.doc_name_bytes
+            + self.metadata_bytes
     }
 }
 
@@ -78,6 +103,9 @@ pub struct TrigramPostingStats {
     // Stats for the successors list
     pub successors: SequenceStats,
 
+    // The size the successors list would have in the matrix layout that was not chosen
+    pub other_layout_successors_bytes: usize,
+
     // Stats for the unique doc IDs
     pub unique_docs: SequenceStats,
 }
@@ -88,6 +116,7 @@ impl TrigramPostingStats {
             header_bytes: usize::MAX,
             unique_successors: SequenceStats::new_max(),
             successors: SequenceStats::new_max(),
+            other_layout_successors_bytes: usize::MAX,
             unique_docs: SequenceStats::new_max(),
         }
     }
@@ -105,6 +134,9 @@ impl TrigramPostingStats {
             unique_successors: self.unique_successors.max(&other.unique_successors),
             unique_docs: self.unique_docs.max(&other.unique_docs),
             successors: self.successors.max(&other.successors),
+            other_layout_successors_bytes: self
+                .other_layout_successors_bytes
+                .max(other.other_layout_successors_bytes),
         }
     }
 
@@ -114,6 +146,9 @@ impl TrigramPostingStats {
             unique_successors: self.unique_successors.min(&other.unique_successors),
             unique_docs: self.unique_docs.min(&other.unique_docs),
             successors: self.successors.min(&other.successors),
+            other_layout_successors_bytes: self
+                .other_layout_successors_bytes
+                .min(other.other_layout_successors_bytes),
         }
     }
 
@@ -123,6 +158,8 @@ impl TrigramPostingStats {
             unique_successors: self.unique_successors.sum(&other.unique_successors),
             unique_docs: self.unique_docs.sum(&other.unique_docs),
             successors: self.successors.sum(&other.successors),
+            other_layout_successors_bytes: self.other_layout_successors_bytes
+                + other.other_layout_successors_bytes,
         }
     }
 }
@@ -0,0 +1,76 @@
+use std::fmt;
+use std::io;
+
+use crate::DocID;
+
+// An error reading an index. Malformed data is reported as `Corrupt` rather than causing a panic,
+// so a bad file only fails the operations that read it.
+#[derive(Debug)]
+pub enum TridentError {
+    // Reading the file failed
+    Io(io::Error),
+
+    // The file does not end with the index magic, so it is not an index at all
+    NotAnIndex,
+
+    // The index was written in a format version that this build cannot read
+    UnsupportedVersion(u32),
+
+    // The index data is malformed, such as a section that is out of bounds or a value that does not
+    // decode
+    Corrupt(String),
+
+    // The doc ID is not in the index
+    DocOutOfRange(DocID),
+
+    // The index does not store the content of its docs
+    NoContent,
+}
+
+pub type Result<T, E = TridentError> = std::result::Result<T, E>;
+
+impl TridentError {
+    pub fn corrupt(message: impl Into<String>) -> Self {
+        TridentError::Corrupt(message.into())
+    }
+}
+
+impl fmt::Display for TridentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TridentError::Io(err) => write!(f, "read index: {}", err),
+            TridentError::NotAnIndex => write!(f, "not a trident index"),
+            TridentError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported index format version {} (this build reads version {})",
+                version,
+                crate::index::FORMAT_VERSION
+            ),
+            TridentError::Corrupt(message) => write!(f, "corrupt index: {}", message),
+            TridentError::DocOutOfRange(doc_id) => write!(f, "doc ID {} out of range", doc_id),
+            TridentError::NoContent => write!(f, "index does not store doc contents"),
+        }
+    }
+}
+
+impl std::error::Error for TridentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TridentError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TridentError {
+    // Reads are confined to the section that holds the data, so running out of data or failing to
+    // decode it means the index is corrupt rather than that the file could not be read
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => {
+                TridentError::Corrupt(err.to_string())
+            }
+            _ => TridentError::Io(err),
+        }
+    }
+}
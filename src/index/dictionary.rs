@@ -0,0 +1,298 @@
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{reader_in, TrigramPostingEndsSection, TrigramPresenceSection, UniqueTrigramsSection};
+use crate::error::{Result, TridentError};
+use crate::ioutil::{ReadAt, Section};
+use crate::{Trigram, TrigramID};
+
+// The number of trigrams that are read at once to finish a lookup. The first trigram of each
+// block is kept in a sparse sample that is binary searched in memory to find the block, and the
+// block is then read and searched.
+const BLOCK_LEN: u64 = 64;
+
+// The presence table has a bit for every possible trigram, indexed by `TrigramID`, in blocks of
+// this many bits. Each block is stored as the number of trigrams before it followed by its bits,
+// so the rank of a trigram takes a single read.
+const PRESENCE_BLOCK_BITS: u64 = 512;
+const PRESENCE_BLOCK_BYTES: u64 = 4 + PRESENCE_BLOCK_BITS / 8;
+
+// The size of the presence table, which is the same for every index that has one
+pub const PRESENCE_TABLE_BYTES: u64 = (1 << 24) / PRESENCE_BLOCK_BITS * PRESENCE_BLOCK_BYTES;
+
+// The sorted unique trigrams of an index and the end of each one's posting, which are searched in
+// place through `ReadAt` rather than loaded, so that opening an index doesn't read them. The
+// first lookup loads a sample of the first trigram of each block, after which a lookup takes one
+// read of a block and one of the posting ends. An index with many trigrams can also have a
+// presence table, which replaces the sample with one read of the table.
+#[derive(Debug, Clone)]
+pub struct TrigramDictionary {
+    trigrams: UniqueTrigramsSection,
+    posting_ends: TrigramPostingEndsSection,
+    presence: Option<TrigramPresenceSection>,
+
+    // Shared by the clones of an index, which read the same data
+    sample: Arc<OnceLock<Vec<Trigram>>>,
+}
+
+impl TrigramDictionary {
+    pub fn new(
+        trigrams: UniqueTrigramsSection,
+        posting_ends: TrigramPostingEndsSection,
+        presence: TrigramPresenceSection,
+    ) -> Self {
+        Self {
+            trigrams,
+            posting_ends,
+            presence: (presence.len > 0).then_some(presence),
+            sample: Arc::default(),
+        }
+    }
+
+    // Returns the number of unique trigrams
+    pub fn len(&self) -> u64 {
+        self.trigrams.len / 3
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn has_presence_table(&self) -> bool {
+        self.presence.is_some()
+    }
+
+    // Returns the index of a trigram, if it exists
+    pub fn find<R: ReadAt>(&self, r: &R, t: Trigram) -> Result<Option<u64>> {
+        let (idx, found) = self.search(r, t)?;
+        Ok(found.then_some(idx))
+    }
+
+    // Returns the index of the first trigram that is at least `t`
+    pub fn lower_bound<R: ReadAt>(&self, r: &R, t: Trigram) -> Result<u64> {
+        Ok(self.search(r, t)?.0)
+    }
+
+    // Returns the index of the first trigram that is at least `t`, and whether it is `t`
+    fn search<R: ReadAt>(&self, r: &R, t: Trigram) -> Result<(u64, bool)> {
+        if let Some(presence) = self.presence {
+            return self.rank(r, presence, t);
+        }
+
+        // Find the last block that starts at or before `t`, which is the only one that can hold it
+        let blocks = self.sample(r)?.partition_point(|&first| first <= t) as u64;
+        if blocks == 0 {
+            return Ok((0, false));
+        }
+
+        let start = (blocks - 1) * BLOCK_LEN;
+        let block = self.read_trigrams(r, start..(start + BLOCK_LEN).min(self.len()))?;
+        let idx = block.partition_point(|&b| b < t);
+        Ok((start + idx as u64, block.get(idx) == Some(&t)))
+    }
+
+    // Returns the number of trigrams before `t` according to the presence table, which is the
+    // index of the first trigram that is at least `t`, and whether `t` is present
+    fn rank<R: ReadAt>(&self, r: &R, presence: Section, t: Trigram) -> Result<(u64, bool)> {
+        let id = TrigramID::from(t) as u64;
+        let mut buf = [0u8; PRESENCE_BLOCK_BYTES as usize];
+        r.read_exact_at(
+            &mut buf,
+            presence.offset + id / PRESENCE_BLOCK_BITS * PRESENCE_BLOCK_BYTES,
+        )?;
+        let mut block = &buf[..];
+        let mut rank = block.read_u32::<LittleEndian>()? as u64;
+        let mut words = [0u64; (PRESENCE_BLOCK_BITS / 64) as usize];
+        block.read_u64_into::<LittleEndian>(&mut words)?;
+
+        let bit = id % PRESENCE_BLOCK_BITS;
+        let (word, bit) = ((bit / 64) as usize, bit % 64);
+        rank += words[..word]
+            .iter()
+            .map(|w| w.count_ones() as u64)
+            .sum::<u64>();
+        rank += (words[word] & ((1 << bit) - 1)).count_ones() as u64;
+        let found = (words[word] >> bit) & 1 == 1;
+        if rank + found as u64 > self.len() {
+            return Err(TridentError::corrupt(format!(
+                "presence table ranks {:?} at {}, past the {} unique trigrams",
+                t,
+                rank,
+                self.len()
+            )));
+        }
+        Ok((rank, found))
+    }
+
+    // Returns the first trigram of each block, reading them on the first call
+    fn sample<R: ReadAt>(&self, r: &R) -> Result<&[Trigram]> {
+        if let Some(sample) = self.sample.get() {
+            return Ok(sample);
+        }
+
+        let mut sample = Vec::with_capacity(self.len().div_ceil(BLOCK_LEN) as usize);
+        let mut trigrams = reader_in(r, self.trigrams);
+        let mut block = vec![0u8; BLOCK_LEN as usize * 3];
+        for start in (0..self.len()).step_by(BLOCK_LEN as usize) {
+            let block = &mut block[..(self.len() - start).min(BLOCK_LEN) as usize * 3];
+            trigrams.read_exact(block)?;
+            sample.push(Trigram([block[0], block[1], block[2]]));
+        }
+        // Another clone may have loaded the sample first, in which case both are the same
+        Ok(self.sample.get_or_init(|| sample))
+    }
+
+    fn read_trigrams<R: ReadAt>(&self, r: &R, range: Range<u64>) -> Result<Vec<Trigram>> {
+        let mut buf = vec![0u8; (range.end - range.start) as usize * 3];
+        r.read_exact_at(&mut buf, self.trigrams.offset + range.start * 3)?;
+        Ok(buf
+            .chunks_exact(3)
+            .map(|t| Trigram([t[0], t[1], t[2]]))
+            .collect())
+    }
+
+    // Returns the range of the posting of the trigram at an index within the postings section
+    pub fn posting_range<R: ReadAt>(&self, r: &R, idx: u64) -> Result<Range<u64>> {
+        let mut buf = [0u8; 16];
+        let ends = match idx {
+            0 => &mut buf[8..],
+            _ => &mut buf[..],
+        };
+        let offset = self.posting_ends.offset + idx.saturating_sub(1) * 8;
+        r.read_exact_at(ends, offset)?;
+        let mut ends = &buf[..];
+        Ok(ends.read_u64::<LittleEndian>()?..ends.read_u64::<LittleEndian>()?)
+    }
+
+    // Returns every trigram from an index onward in order, along with the range of its posting
+    // within the postings section. The trigrams and their ends are read sequentially.
+    pub fn entries<'a, R: ReadAt>(
+        &self,
+        r: &'a R,
+        from: u64,
+    ) -> impl Iterator<Item = Result<(Trigram, Range<u64>)>> + 'a {
+        let skip = |section: Section, bytes: u64| {
+            Section::new(
+                section.offset + bytes.min(section.len),
+                section.len.saturating_sub(bytes),
+            )
+        };
+        let mut trigrams = reader_in(r, skip(self.trigrams, from * 3));
+        // Each posting starts where the one before it ends
+        let mut ends = reader_in(r, skip(self.posting_ends, from.saturating_sub(1) * 8));
+        let mut last_end = None;
+        (from..self.len()).map(move |idx| {
+            let start = match last_end {
+                Some(end) => end,
+                None if idx == 0 => 0,
+                None => ends.read_u64::<LittleEndian>()?,
+            };
+            let mut t = [0u8; 3];
+            trigrams.read_exact(&mut t)?;
+            let end = ends.read_u64::<LittleEndian>()?;
+            last_end = Some(end);
+            Ok((Trigram(t), start..end))
+        })
+    }
+}
+
+// Writes the presence table for the sorted unique trigrams of an index. Returns the number of bytes
+// written.
+pub fn write_presence_table<W: Write>(
+    w: &mut W,
+    trigrams: impl Iterator<Item = Trigram>,
+) -> io::Result<u64> {
+    let mut words = vec![0u64; (1 << 24) / 64];
+    for t in trigrams {
+        let id = TrigramID::from(t) as usize;
+        words[id / 64] |= 1 << (id % 64);
+    }
+
+    let mut rank = 0;
+    for block in words.chunks_exact((PRESENCE_BLOCK_BITS / 64) as usize) {
+        w.write_u32::<LittleEndian>(rank)?;
+        for word in block {
+            w.write_u64::<LittleEndian>(*word)?;
+            rank += word.count_ones();
+        }
+    }
+    Ok(PRESENCE_TABLE_BYTES)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build::IndexBuilder;
+    use crate::index::Index;
+    use crate::ioutil::Mem;
+
+    #[test]
+    fn test_lookup() {
+        // Without a presence table the trigrams are found through the sample, and with one they
+        // are ranked
+        for min_trigrams in [usize::MAX, 0] {
+            let mut builder = IndexBuilder::new().presence_table_min_trigrams(min_trigrams);
+            for i in 0..20 {
+                let words: Vec<String> = (0..200)
+                    .map(|j| format!("{:03x}", (i * 200 + j) * 7919 % 4096))
+                    .collect();
+                builder
+                    .add_doc(&format!("doc{}", i), words.join(" ").as_bytes())
+                    .unwrap();
+            }
+            let mut output = Vec::new();
+            builder.build(&mut output).unwrap();
+            let index = Index::new(Mem(output)).unwrap();
+            let (dictionary, r) = (&index.dictionary, &index.r);
+            assert_eq!(dictionary.has_presence_table(), min_trigrams == 0);
+            // Opening the index doesn't read the sample
+            assert!(dictionary.sample.get().is_none());
+
+            let entries: Vec<(Trigram, Range<u64>)> =
+                dictionary.entries(r, 0).map(Result::unwrap).collect();
+            assert_eq!(entries.len() as u64, dictionary.len());
+            assert!(dictionary.len() > 10 * BLOCK_LEN);
+            let trigrams: Vec<Trigram> = entries.iter().map(|(t, _)| *t).collect();
+
+            for (idx, (trigram, range)) in entries.iter().enumerate() {
+                assert_eq!(dictionary.find(r, *trigram).unwrap(), Some(idx as u64));
+                assert_eq!(&dictionary.posting_range(r, idx as u64).unwrap(), range);
+            }
+            assert_eq!(dictionary.sample.get().is_some(), min_trigrams != 0);
+            if let Some(sample) = dictionary.sample.get() {
+                let firsts: Vec<Trigram> = trigrams
+                    .iter()
+                    .step_by(BLOCK_LEN as usize)
+                    .copied()
+                    .collect();
+                assert_eq!(sample, &firsts);
+            }
+
+            // Trigrams between, before and after the ones in the index
+            let mut absent = vec![Trigram([0; 3]), Trigram([0xff; 3])];
+            for t in trigrams.iter() {
+                let mut next = t.0;
+                next[2] = next[2].wrapping_add(1);
+                absent.push(Trigram(next));
+            }
+            for t in absent {
+                let expected = trigrams.partition_point(|&e| e < t);
+                assert_eq!(dictionary.lower_bound(r, t).unwrap(), expected as u64);
+                let found = trigrams.get(expected) == Some(&t);
+                assert_eq!(dictionary.find(r, t).unwrap().is_some(), found);
+            }
+
+            let from = 3 * BLOCK_LEN as usize + 5;
+            let tail: Vec<_> = dictionary
+                .entries(r, from as u64)
+                .map(Result::unwrap)
+                .collect();
+            assert_eq!(tail, entries[from..]);
+            assert_eq!(dictionary.entries(r, dictionary.len()).count(), 0);
+            assert!(index.verify().unwrap().is_empty());
+        }
+    }
+}
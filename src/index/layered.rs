@@ -0,0 +1,298 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::matches::DocMatch;
+use super::{Concatenation, DocIterator, Index};
+use crate::bitmap::DocBitmap;
+use crate::build::serialize::StreamWriter;
+use crate::ioutil::{write_atomically, Len, ReadAt};
+use crate::query::SearchQuery;
+use crate::DocID;
+
+// A base index with delta indexes appended to it, searched as a single index. The doc IDs of each
+// layer follow those of the layers before it. When docs change after the base is built, their old
+// versions are deleted from the layers that have them and the new versions are built into a delta,
+// so queries across the layers only see the latest version of each doc.
+#[derive(Clone)]
+pub struct LayeredIndex<R> {
+    layers: Vec<Index<R>>,
+
+    // The first doc ID of each layer
+    offsets: Vec<DocID>,
+}
+
+impl<R: ReadAt + Len> LayeredIndex<R> {
+    pub fn new(base: Index<R>) -> Self {
+        Self {
+            layers: vec![base],
+            offsets: vec![0],
+        }
+    }
+
+    // Creates a layered index from its layers in doc ID order, which may be empty
+    pub fn from_layers(layers: impl IntoIterator<Item = Index<R>>) -> Result<Self> {
+        let mut layered = Self {
+            layers: Vec::new(),
+            offsets: Vec::new(),
+        };
+        for layer in layers {
+            layered.push_delta(layer)?;
+        }
+        Ok(layered)
+    }
+
+    // Appends a delta index, whose docs get the doc IDs after every existing layer
+    pub fn push_delta(&mut self, delta: Index<R>) -> Result<()> {
+        self.num_docs()
+            .checked_add(delta.num_docs())
+            .ok_or_else(|| anyhow!("too many docs across layers"))?;
+        self.offsets.push(self.num_docs());
+        self.layers.push(delta);
+        Ok(())
+    }
+
+    pub fn layers(&self) -> &[Index<R>] {
+        &self.layers
+    }
+
+    pub fn num_docs(&self) -> u32 {
+        match self.layers.last() {
+            Some(last) => self.offsets.last().unwrap() + last.num_docs(),
+            None => 0,
+        }
+    }
+
+    // Returns the index of the layer that holds a doc, and the doc's ID within it
+    fn locate(&self, doc_id: DocID) -> Result<(usize, DocID)> {
+        if doc_id >= self.num_docs() {
+            return Err(anyhow!("doc ID {} out of range", doc_id));
+        }
+        let layer = self.offsets.partition_point(|&offset| offset <= doc_id) - 1;
+        Ok((layer, doc_id - self.offsets[layer]))
+    }
+
+    pub fn doc_name(&self, doc_id: DocID) -> Result<String> {
+        let (layer, doc_id) = self.locate(doc_id)?;
+        Ok(self.layers[layer].doc_name(doc_id)?)
+    }
+
+    // Returns whether every layer stores the content of its docs
+    pub fn has_content(&self) -> bool {
+        self.layers.iter().all(|layer| layer.has_content())
+    }
+
+    pub fn content(&self, doc_id: DocID) -> Result<Vec<u8>> {
+        let (layer, doc_id) = self.locate(doc_id)?;
+        Ok(self.layers[layer].content(doc_id)?)
+    }
+
+    pub fn delete(&mut self, doc_id: DocID) -> Result<()> {
+        let (layer, doc_id) = self.locate(doc_id)?;
+        Ok(self.layers[layer].delete(doc_id)?)
+    }
+
+    // Deletes every doc that was added with the given name from every layer. Returns the number of
+    // docs deleted.
+    pub fn delete_named(&mut self, name: &str) -> Result<usize> {
+        let mut deleted = 0;
+        for layer in self.layers.iter_mut() {
+            for doc_id in layer.find_docs(name)? {
+                layer.delete(doc_id)?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    pub fn candidates<'a, Q: SearchQuery + ?Sized>(
+        &'a self,
+        query: &Q,
+    ) -> Box<dyn DocIterator + 'a> {
+        Box::new(Concatenation::new(
+            self.offsets
+                .iter()
+                .zip(self.layers.iter())
+                .map(|(&offset, layer)| (offset, query.candidates(layer)))
+                .collect(),
+        ))
+    }
+
+    pub fn search<Q: SearchQuery + ?Sized>(&self, query: &Q) -> Result<Vec<DocID>> {
+        let mut matches = Vec::new();
+        for (&offset, layer) in self.offsets.iter().zip(self.layers.iter()) {
+            matches.extend(layer.search(query)?.into_iter().map(|d| d + offset));
+        }
+        Ok(matches)
+    }
+
+    pub fn search_matches<Q: SearchQuery + ?Sized>(
+        &self,
+        query: &Q,
+        context: usize,
+    ) -> Result<Vec<DocMatch>> {
+        let mut doc_matches = Vec::new();
+        for (&offset, layer) in self.offsets.iter().zip(self.layers.iter()) {
+            doc_matches.extend(layer.search_matches(query, context)?.into_iter().map(
+                |mut doc_match| {
+                    doc_match.doc_id += offset;
+                    doc_match
+                },
+            ));
+        }
+        Ok(doc_matches)
+    }
+}
+
+// Returns the path of the sidecar file that holds the tombstones of an index file
+pub fn tombstones_path(index_path: &Path) -> PathBuf {
+    let mut path = index_path.as_os_str().to_owned();
+    path.push(".deleted");
+    PathBuf::from(path)
+}
+
+// Reads the tombstones of an index from its sidecar file, if it has one. The sidecar starts with
+// the checksum of the index it was written for, so tombstones left behind by an index that has
+// since been rebuilt at the same path are rejected rather than applied to unrelated docs.
+pub fn read_tombstones<R: ReadAt + Len>(index: &mut Index<R>, index_path: &Path) -> Result<()> {
+    let path = tombstones_path(index_path);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let mut r = BufReader::new(File::open(&path)?);
+    let checksum = r.read_u32::<LittleEndian>()?;
+    if checksum != index.checksum() {
+        return Err(anyhow!(
+            "tombstones {:?} were written for another index (checksum {:08x}, expected {:08x})",
+            path,
+            checksum,
+            index.checksum()
+        ));
+    }
+    index.set_deleted(DocBitmap::read_from(&mut r)?)?;
+    Ok(())
+}
+
+// Writes the tombstones of an index to its sidecar file, if it has deleted docs
+pub fn write_tombstones<R: ReadAt + Len>(index: &Index<R>, index_path: &Path) -> Result<()> {
+    if let Some(deleted) = index.deleted() {
+        write_atomically(&tombstones_path(index_path), |w| {
+            w.write_u32::<LittleEndian>(index.checksum())?;
+            deleted.write_to(w).map(|_| ())
+        })?;
+    }
+    Ok(())
+}
+
+impl LayeredIndex<File> {
+    // Opens a base index and its deltas, along with the tombstones of any of them that have a
+    // sidecar file
+    pub fn open(base_path: &Path, delta_paths: &[PathBuf]) -> Result<Self> {
+        let open = |path: &Path| -> Result<Index<File>> {
+            let mut index =
+                Index::new(File::open(path)?).with_context(|| format!("open index {:?}", path))?;
+            read_tombstones(&mut index, path)?;
+            Ok(index)
+        };
+
+        let mut layered = Self::new(open(base_path)?);
+        for path in delta_paths {
+            layered.push_delta(open(path)?)?;
+        }
+        Ok(layered)
+    }
+
+    // Writes the tombstones of each layer that has deleted docs to its sidecar file. The paths
+    // must be those that the layers were opened with.
+    pub fn write_tombstones(&self, base_path: &Path, delta_paths: &[PathBuf]) -> Result<()> {
+        let paths = std::iter::once(base_path).chain(delta_paths.iter().map(PathBuf::as_path));
+        for (layer, path) in self.layers.iter().zip(paths) {
+            write_tombstones(layer, path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::build::IndexBuilder;
+    use crate::ioutil::Mem;
+    use crate::query::Query;
+
+    fn build(docs: &[(&str, &str)]) -> Index<Mem> {
+        let mut builder = IndexBuilder::new().store_content(true);
+        for (name, content) in docs {
+            builder.add_doc(name, content.as_bytes()).unwrap();
+        }
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        Index::new(Mem(output)).unwrap()
+    }
+
+    #[test]
+    fn test_layered_search() {
+        let base = build(&[
+            ("a.rs", "fn old_name() {}"),
+            ("b.rs", "fn other() {}"),
+            ("c.rs", "fn old_name() { other() }"),
+        ]);
+        let mut index = LayeredIndex::new(base);
+
+        // Change a.rs and delete c.rs
+        assert_eq!(index.delete_named("a.rs").unwrap(), 1);
+        assert_eq!(index.delete_named("c.rs").unwrap(), 1);
+        index
+            .push_delta(build(&[("a.rs", "fn new_name() { other() }")]))
+            .unwrap();
+
+        assert_eq!(index.num_docs(), 4);
+        assert_eq!(index.doc_name(3).unwrap(), "a.rs");
+
+        let query = Query::parse("other").unwrap();
+        assert_eq!(index.candidates(&query).collect::<Vec<_>>(), &[1, 3]);
+        assert_eq!(index.search(&query).unwrap(), &[1, 3]);
+        let query = Query::parse("old_name").unwrap();
+        assert_eq!(index.candidates(&query).count(), 0);
+
+        // A negated query must not bring back deleted docs
+        let query = Query::parse("NOT new").unwrap();
+        assert_eq!(index.candidates(&query).collect::<Vec<_>>(), &[1]);
+
+        let mut candidates = index.candidates(&Query::parse("fn").unwrap());
+        assert_eq!(candidates.advance_to(2), Some(3));
+        assert_eq!(candidates.next(), None);
+    }
+
+    #[test]
+    fn test_tombstones_sidecar() {
+        let dir = std::env::temp_dir().join(format!("trident-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("base.idx");
+        let write = |content: &str| {
+            let mut builder = IndexBuilder::new();
+            builder.add_doc("a.rs", content.as_bytes()).unwrap();
+            builder.add_doc("b.rs", b"fn other() {}").unwrap();
+            builder.build(&mut File::create(&path).unwrap()).unwrap();
+        };
+
+        write("fn old_name() {}");
+        let mut index = LayeredIndex::open(&path, &[]).unwrap();
+        assert_eq!(index.delete_named("a.rs").unwrap(), 1);
+        index.write_tombstones(&path, &[]).unwrap();
+        let index = LayeredIndex::open(&path, &[]).unwrap();
+        assert!(index.layers()[0].is_deleted(0));
+
+        // An index rebuilt with the same number of docs doesn't get the old tombstones
+        write("fn new_name() {}");
+        let err = LayeredIndex::open(&path, &[]).err().unwrap();
+        assert!(err.to_string().contains("were written for another index"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use std::ops::Range;
+
+use crate::DocID;
+
+// A doc that matches a query, along with the location of every match in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocMatch {
+    pub doc_id: DocID,
+    pub matches: Vec<Match>,
+}
+
+// A single occurrence of a match in a doc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    // The byte range of the match in the doc
+    pub range: Range<usize>,
+
+    // The 1-based number of the line that the match starts on
+    pub line_number: usize,
+
+    // The 1-based byte offset of the start of the match in its line
+    pub column: usize,
+
+    // The line that the match starts on, without its line terminator
+    pub line: Vec<u8>,
+
+    // Up to the requested number of lines before and after the line of the match
+    pub before: Vec<Vec<u8>>,
+    pub after: Vec<Vec<u8>>,
+}
+
+impl Match {
+    // Locates each of the sorted byte ranges in the content, including `context` lines before
+    // and after each one.
+    pub fn locate(
+        content: &[u8],
+        ranges: impl IntoIterator<Item = Range<usize>>,
+        context: usize,
+    ) -> Vec<Match> {
+        let mut line_starts = vec![0];
+        line_starts.extend(memchr::memchr_iter(b'\n', content).map(|i| i + 1));
+        // A trailing newline terminates the last line rather than starting a new one
+        if content.last() == Some(&b'\n') {
+            line_starts.pop();
+        }
+
+        let line = |i: usize| {
+            let start = line_starts[i];
+            let end = match line_starts.get(i + 1) {
+                Some(next) => next - 1,
+                None => content.len() - (content.last() == Some(&b'\n')) as usize,
+            };
+            content[start..end].to_vec()
+        };
+
+        ranges
+            .into_iter()
+            .map(|range| {
+                let i = line_starts.partition_point(|&s| s <= range.start) - 1;
+                Match {
+                    line_number: i + 1,
+                    column: range.start - line_starts[i] + 1,
+                    line: line(i),
+                    before: (i.saturating_sub(context)..i).map(line).collect(),
+                    after: (i + 1..line_starts.len().min(i + 1 + context))
+                        .map(line)
+                        .collect(),
+                    range,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let content = b"fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n";
+        let matches = Match::locate(content, [3..7, 20..21, 45..46], 1);
+
+        assert_eq!(matches[0].line_number, 1);
+        assert_eq!(matches[0].column, 4);
+        assert_eq!(matches[0].line, b"fn main() {");
+        assert!(matches[0].before.is_empty());
+        assert_eq!(matches[0].after, vec![b"    let x = 1;".to_vec()]);
+
+        assert_eq!(matches[1].line_number, 2);
+        assert_eq!(matches[1].column, 9);
+        assert_eq!(matches[1].before, vec![b"fn main() {".to_vec()]);
+        assert_eq!(matches[1].after, vec![b"    println!(\"{}\", x);".to_vec()]);
+
+        assert_eq!(matches[2].line_number, 3);
+        assert_eq!(matches[2].column, 19);
+        assert_eq!(matches[2].after, vec![b"}".to_vec()]);
+    }
+
+    #[test]
+    // The ranges of one match, not a range to expand
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_locate_no_trailing_newline() {
+        let content = b"abc\ndef";
+        let matches = Match::locate(content, [4..7], 2);
+        assert_eq!(matches[0].line_number, 2);
+        assert_eq!(matches[0].column, 1);
+        assert_eq!(matches[0].line, b"def");
+        assert_eq!(matches[0].before, vec![b"abc".to_vec()]);
+        assert!(matches[0].after.is_empty());
+    }
+}
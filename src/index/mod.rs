@@ -0,0 +1,2400 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::BufReader;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use integer_encoding::VarIntReader;
+use itertools::Itertools;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use self::dictionary::{TrigramDictionary, PRESENCE_TABLE_BYTES};
+use super::ioutil::Section;
+use crate::bitmap::DocBitmap;
+use crate::build::serialize::{
+    FrontCodedDecoder, MatrixDecompressor, U32DeltaDecompressor, FRONT_CODED_BLOCK_LEN,
+};
+use crate::error::{Result, TridentError};
+use crate::index::matches::{DocMatch, Match};
+use crate::index::plan::QueryPlan;
+use crate::ioutil::{section_checksum, Cursor, Len, ReadAt, SectionType};
+use crate::query::regex::RegexQuery;
+use crate::query::{Case, Filter, Query, SearchQuery, TrigramQuery};
+use crate::{build::serialize::StreamWriter, DocID, LocalDocIdx, Trigram};
+use crate::{LocalSuccessorIdx, TrigramID};
+
+pub mod dictionary;
+pub mod layered;
+pub mod matches;
+pub mod plan;
+pub mod segmented;
+pub mod sharded;
+
+// An index can be cloned cheaply if its reader can. Clones share their tombstones until one of
+// them deletes a doc.
+#[derive(Clone)]
+pub struct Index<R> {
+    header: IndexHeader,
+    dictionary: TrigramDictionary,
+
+    // Docs that have been deleted since the index was built, which are removed from every stream
+    // of candidates
+    deleted: Option<Arc<DocBitmap>>,
+    r: R,
+}
+
+impl<R> Index<R>
+where
+    R: ReadAt + Len,
+{
+    pub fn new(r: R) -> Result<Self> {
+        let header = Self::read_header(&r)?;
+
+        // The trigram dictionary is searched in place, so check up front that it is within the
+        // file
+        let data_len = r.len()? - IndexHeader::SIZE_BYTES as u64;
+        let dictionary_sections = [
+            header.unique_trigrams,
+            header.trigram_posting_ends,
+            header.trigram_presence,
+        ];
+        for section in dictionary_sections {
+            if section
+                .offset
+                .checked_add(section.len)
+                .is_none_or(|end| end > data_len)
+            {
+                return Err(TridentError::corrupt(format!(
+                    "trigram dictionary at {}+{} is past the end of the data at {}",
+                    section.offset, section.len, data_len
+                )));
+            }
+        }
+
+        Ok(Self {
+            dictionary: TrigramDictionary::new(
+                header.unique_trigrams,
+                header.trigram_posting_ends,
+                header.trigram_presence,
+            ),
+            header,
+            deleted: None,
+            r,
+        })
+    }
+
+    fn read_header<T: ReadAt + Len>(r: &T) -> Result<IndexHeader> {
+        // Check the trailer first, since the size of the rest of the header depends on the version
+        let len = r.len()?;
+        if len < IndexHeader::TRAILER_BYTES as u64 {
+            return Err(TridentError::NotAnIndex);
+        }
+        let mut cursor = Cursor::new(r);
+        cursor.seek(SeekFrom::End(-(IndexHeader::TRAILER_BYTES as i64)))?;
+        IndexHeader::read_trailer(&mut cursor)?;
+
+        if len < IndexHeader::SIZE_BYTES as u64 {
+            return Err(TridentError::corrupt(format!(
+                "index is truncated: only {} bytes long",
+                len
+            )));
+        }
+        cursor.seek(SeekFrom::End(-(IndexHeader::SIZE_BYTES as i64)))?;
+        IndexHeader::read_from(&mut cursor)
+    }
+
+    pub fn num_docs(&self) -> u32 {
+        self.header.num_docs
+    }
+
+    // Identifies the data of the index by the checksums of its sections, so that files kept
+    // alongside it, like its tombstones, can be checked against the index they were written for
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for checksum in self.header.checksums {
+            hasher.update(&checksum.to_le_bytes());
+        }
+        hasher.finalize()
+    }
+
+    // Returns the docs that have been deleted, if any
+    pub fn deleted(&self) -> Option<&DocBitmap> {
+        self.deleted.as_deref()
+    }
+
+    // Replaces the set of deleted docs, usually with tombstones read from a sidecar file with
+    // `layered::read_tombstones`
+    pub fn set_deleted(&mut self, deleted: DocBitmap) -> Result<()> {
+        if deleted.len() != self.header.num_docs {
+            return Err(TridentError::corrupt(format!(
+                "tombstones for {} docs do not match an index of {} docs",
+                deleted.len(),
+                self.header.num_docs
+            )));
+        }
+        self.deleted = Some(Arc::new(deleted));
+        Ok(())
+    }
+
+    // Marks a doc as deleted, so it is no longer a candidate for any query
+    pub fn delete(&mut self, doc_id: DocID) -> Result<()> {
+        if doc_id >= self.header.num_docs {
+            return Err(TridentError::DocOutOfRange(doc_id));
+        }
+        let num_docs = self.header.num_docs;
+        let deleted = self
+            .deleted
+            .get_or_insert_with(|| Arc::new(DocBitmap::new(num_docs)));
+        Arc::make_mut(deleted).insert(doc_id);
+        Ok(())
+    }
+
+    pub fn is_deleted(&self, doc_id: DocID) -> bool {
+        matches!(&self.deleted, Some(deleted) if deleted.contains(doc_id))
+    }
+
+    // Returns the IDs of the docs that are not deleted and were added with the given name
+    pub fn find_docs(&self, name: &str) -> Result<Vec<DocID>> {
+        let mut doc_ids = Vec::new();
+        for (doc_id, doc_name) in (0..).zip(self.doc_names()) {
+            if doc_name? == name && !self.is_deleted(doc_id) {
+                doc_ids.push(doc_id);
+            }
+        }
+        Ok(doc_ids)
+    }
+
+    // Removes deleted docs from a stream of candidates
+    fn live<'a>(&'a self, docs: Box<dyn DocIterator + 'a>) -> Box<dyn DocIterator + 'a> {
+        match &self.deleted {
+            Some(deleted) => Box::new(Difference::new(docs, Box::new(deleted.iter()))),
+            None => docs,
+        }
+    }
+
+    // Returns the name that the doc was added to the index with
+    pub fn doc_name(&self, doc_id: DocID) -> Result<String> {
+        if doc_id >= self.header.num_docs {
+            return Err(TridentError::DocOutOfRange(doc_id));
+        }
+
+        let block = doc_id as usize / FRONT_CODED_BLOCK_LEN;
+        let mut ends_reader = reader_in(&self.r, self.header.doc_name_block_ends);
+        let start = match block {
+            0 => 0,
+            _ => {
+                ends_reader.seek(SeekFrom::Current((block as i64 - 1) * 8))?;
+                ends_reader.read_u64::<LittleEndian>()?
+            }
+        };
+        let end = ends_reader.read_u64::<LittleEndian>()?;
+
+        let section = self.header.doc_names.narrow(section_between(start, end)?)?;
+        let name = FrontCodedDecoder::new(reader_in(&self.r, section), FRONT_CODED_BLOCK_LEN)
+            .take(doc_id as usize % FRONT_CODED_BLOCK_LEN + 1)
+            .try_fold(Vec::new(), |_, name| name)?;
+        String::from_utf8(name)
+            .map_err(|_| TridentError::corrupt(format!("name of doc {} is not UTF-8", doc_id)))
+    }
+
+    // Returns the name of every doc in doc ID order. The names are decoded in one pass over the
+    // front-coded blocks, rather than from the start of a block for each doc as `doc_name` does.
+    pub fn doc_names(&self) -> impl Iterator<Item = Result<String>> + '_ {
+        let names = FrontCodedDecoder::new(
+            reader_in(&self.r, self.header.doc_names),
+            self.header.num_docs as usize,
+        );
+        names.enumerate().map(|(doc_id, name)| {
+            String::from_utf8(name?)
+                .map_err(|_| TridentError::corrupt(format!("name of doc {} is not UTF-8", doc_id)))
+        })
+    }
+
+    // Returns whether the index stores the content of its docs
+    pub fn has_content(&self) -> bool {
+        self.header.content_ends.len > 0
+    }
+
+    // Returns the content of a doc, which must be stored in the index
+    pub fn content(&self, doc_id: DocID) -> Result<Vec<u8>> {
+        let compressed = self.compressed_content(doc_id)?;
+        let corrupt =
+            |e| TridentError::corrupt(format!("decompress content of doc {}: {}", doc_id, e));
+
+        // LZ4 can't expand data by more than 255 times, so a larger size is corrupt and would
+        // only be a wasted allocation
+        let size = match compressed.first_chunk::<4>() {
+            Some(size) => u32::from_le_bytes(*size) as usize,
+            None => return Err(corrupt("missing size".to_string())),
+        };
+        if size > compressed.len().saturating_mul(255) {
+            return Err(corrupt(format!("size {} is too large", size)));
+        }
+        lz4_flex::decompress_size_prepended(&compressed).map_err(|e| corrupt(e.to_string()))
+    }
+
+    // Returns the stored content of a doc as it was compressed by the builder
+    pub(crate) fn compressed_content(&self, doc_id: DocID) -> Result<Vec<u8>> {
+        if !self.has_content() {
+            return Err(TridentError::NoContent);
+        }
+        if doc_id >= self.header.num_docs {
+            return Err(TridentError::DocOutOfRange(doc_id));
+        }
+
+        let mut ends_reader = reader_in(&self.r, self.header.content_ends);
+        let start = match doc_id {
+            0 => 0,
+            _ => {
+                ends_reader.seek(SeekFrom::Current((doc_id as i64 - 1) * 8))?;
+                ends_reader.read_u64::<LittleEndian>()?
+            }
+        };
+        let end = ends_reader.read_u64::<LittleEndian>()?;
+
+        let section = self.header.contents.narrow(section_between(start, end)?)?;
+        read_bytes(&mut Cursor::new_in(&self.r, section), section.len)
+    }
+
+    // Returns the docs that have the given value for a metadata key
+    pub fn metadata_docs(&self, key: &str, value: &str) -> Result<DocBitmap> {
+        let mut docs = DocBitmap::new(self.header.num_docs);
+        let mut reader = reader_in(&self.r, self.header.metadata);
+        let count: usize = reader.read_varint()?;
+        for _ in 0..count {
+            let entry_key = read_string(&mut reader)?;
+            let entry_value = read_string(&mut reader)?;
+            let docs_count: usize = reader.read_varint()?;
+            let docs_bytes: usize = reader.read_varint()?;
+
+            // Entries are sorted, so stop once we're past the one we're looking for
+            match (entry_key.as_str(), entry_value.as_str()).cmp(&(key, value)) {
+                Ordering::Less => reader.seek_relative(docs_bytes as i64)?,
+                Ordering::Equal => {
+                    let buf = read_bytes(&mut reader, docs_bytes as u64)?;
+                    decode_docs(buf, docs_count, self.header.num_docs)?
+                        .into_iter()
+                        .for_each(|d| docs.insert(d));
+                    break;
+                }
+                Ordering::Greater => break,
+            }
+        }
+        Ok(docs)
+    }
+
+    // Returns every metadata key and value along with the sorted docs that have them
+    pub fn metadata(&self) -> Result<Vec<(String, String, Vec<DocID>)>> {
+        let mut reader = reader_in(&self.r, self.header.metadata);
+        let count: usize = reader.read_varint()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let key = read_string(&mut reader)?;
+            let value = read_string(&mut reader)?;
+            let docs_count: usize = reader.read_varint()?;
+            let docs_bytes: u64 = reader.read_varint()?;
+            let buf = read_bytes(&mut reader, docs_bytes)?;
+            let docs = decode_docs(buf, docs_count, self.header.num_docs)?;
+            entries.push((key, value, docs));
+        }
+        Ok(entries)
+    }
+
+    // Returns the docs that pass every filter and are not deleted
+    pub fn filter_docs(&self, filters: &[Filter]) -> Result<DocBitmap> {
+        let mut docs = DocBitmap::full(self.header.num_docs);
+        for filter in filters {
+            docs.intersect_with(&self.metadata_docs(&filter.key, &filter.value)?);
+        }
+        if let Some(deleted) = &self.deleted {
+            docs.difference_with(deleted);
+        }
+        Ok(docs)
+    }
+
+    // Returns the candidates that pass every filter, or every doc that passes them if there are no
+    // candidates to filter.
+    pub fn filtered_candidates<'a>(
+        &'a self,
+        candidates: Option<Box<dyn DocIterator + 'a>>,
+        filters: &[Filter],
+    ) -> Result<Box<dyn DocIterator + 'a>> {
+        let docs = Box::new(self.filter_docs(filters)?.into_iter());
+        Ok(match candidates {
+            Some(candidates) => Box::new(Intersection::new(vec![candidates, docs])),
+            None => docs,
+        })
+    }
+
+    // Returns the IDs of the docs that match a query, verifying each candidate against its
+    // stored content.
+    pub fn search<Q: SearchQuery + ?Sized>(&self, query: &Q) -> Result<Vec<DocID>> {
+        let mut matches = Vec::new();
+        let mut candidates = query.candidates(self);
+        for doc_id in candidates.by_ref() {
+            if query.is_match(&self.content(doc_id)?) {
+                matches.push(doc_id);
+            }
+        }
+        match candidates.take_error() {
+            Some(err) => Err(err),
+            None => Ok(matches),
+        }
+    }
+
+    // Returns the docs that match a query along with the location of each match in them, with up
+    // to `context` lines before and after each match.
+    pub fn search_matches<Q: SearchQuery + ?Sized>(
+        &self,
+        query: &Q,
+        context: usize,
+    ) -> Result<Vec<DocMatch>> {
+        let mut doc_matches = Vec::new();
+        let mut candidates = query.candidates(self);
+        for doc_id in candidates.by_ref() {
+            let content = self.content(doc_id)?;
+            if query.is_match(&content) {
+                doc_matches.push(DocMatch {
+                    doc_id,
+                    matches: Match::locate(&content, query.find_matches(&content), context),
+                });
+            }
+        }
+        match candidates.take_error() {
+            Some(err) => Err(err),
+            None => Ok(doc_matches),
+        }
+    }
+
+    // Returns the posting section for the given trigram, if it exists.
+    fn trigram_section(&self, t: Trigram) -> Result<Option<TrigramPostingSection>> {
+        let trigram_idx = match self.dictionary.find(&self.r, t)? {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+
+        let range = self.dictionary.posting_range(&self.r, trigram_idx)?;
+        section_between(range.start, range.end).map(Some)
+    }
+
+    // Plans the search for a literal query of at least three bytes, using the frequency of each
+    // trigram in the query as the cost of searching its posting. Returns None if any trigram in
+    // the query does not exist, in which case nothing can match.
+    pub fn plan(&self, query: &[u8], case: Case) -> Result<Option<QueryPlan>> {
+        Ok(self.plan_with_sections(query, case)?.map(|(plan, _)| plan))
+    }
+
+    // Plans the search for a literal query, and also returns the posting sections that were
+    // looked up for the plan so that the search doesn't look them up again. The sections hold
+    // every trigram in the query, and for a case-insensitive search their case variants, that
+    // exists in the index.
+    fn plan_with_sections(
+        &self,
+        query: &[u8],
+        case: Case,
+    ) -> Result<Option<(QueryPlan, FxHashMap<Trigram, TrigramPostingSection>)>> {
+        let mut sections = FxHashMap::default();
+        let mut costs = Vec::new();
+        for &t in query.array_windows::<3>() {
+            // An estimate of the relative frequency of the trigram and its case variants
+            let mut frequency = None;
+            for v in case_variants(&t, case) {
+                let v = Trigram::try_from(&v[..]).unwrap();
+                let section = match sections.get(&v) {
+                    Some(&section) => Some(section),
+                    None => self.trigram_section(v)?,
+                };
+                if let Some(section) = section {
+                    sections.insert(v, section);
+                    *frequency.get_or_insert(0.0) +=
+                        section.len as f32 / self.header.trigram_postings.len as f32;
+                }
+            }
+            match frequency {
+                Some(cost) => costs.push(cost),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some((QueryPlan::new(query.len(), &costs), sections)))
+    }
+
+    // Returns an iterator over the candidate document IDs.
+    pub fn candidates<'a>(&'a self, query: &[u8]) -> Box<dyn DocIterator + 'a> {
+        self.candidates_with_case(query, Case::Sensitive)
+    }
+
+    // Returns an iterator over the candidate document IDs. A case-insensitive search matches
+    // ASCII letters in either case.
+    pub fn candidates_with_case<'a>(
+        &'a self,
+        query: &[u8],
+        case: Case,
+    ) -> Box<dyn DocIterator + 'a> {
+        self.live(self.literal_candidates(query, case))
+    }
+
+    // Returns the candidates for a literal, including deleted docs
+    fn literal_candidates<'a>(&'a self, query: &[u8], case: Case) -> Box<dyn DocIterator + 'a> {
+        if query.len() < 3 {
+            return match self.short_candidates(query, case) {
+                Ok(docs) => Box::new(docs.into_iter()),
+                Err(err) => Box::new(Failed::new(err)),
+            };
+        }
+
+        let (plan, sections) = match self.plan_with_sections(query, case) {
+            Ok(Some(p)) => p,
+            // If any of the trigrams don't exist, no doc can match the query.
+            Ok(None) => return Box::new(std::iter::empty()),
+            Err(err) => return Box::new(Failed::new(err)),
+        };
+
+        // The steps are ordered from cheapest to most expensive, so the rarest pair drives the
+        // intersection.
+        let mut doc_iters = Vec::with_capacity(plan.steps.len());
+        for step in plan.steps {
+            match self.pair_candidates(&query[step.range], case, &sections) {
+                Some(docs) => doc_iters.push(docs),
+                None => return Box::new(std::iter::empty()),
+            }
+        }
+
+        match doc_iters.len() {
+            1 => doc_iters.pop().unwrap(),
+            _ => Box::new(Intersection::new(doc_iters)),
+        }
+    }
+
+    // Returns the docs that contain a query shorter than a trigram by unioning the docs of every
+    // trigram that starts with it. The last two bytes of each doc are padded into partial
+    // trigrams, so an occurrence at the end of a doc is also the start of a trigram, and the
+    // trigrams that end with the query would add nothing.
+    fn short_candidates(&self, query: &[u8], case: Case) -> Result<DocBitmap> {
+        if query.is_empty() {
+            return Ok(DocBitmap::full(self.header.num_docs));
+        }
+
+        let mut docs = DocBitmap::new(self.header.num_docs);
+        for prefix in case_variants(query, case) {
+            // The trigrams that start with the prefix follow the prefix padded with zeros
+            let mut first = [0u8; 3];
+            first[..prefix.len()].copy_from_slice(&prefix);
+            let start = self.dictionary.lower_bound(&self.r, Trigram(first))?;
+            for entry in self.dictionary.entries(&self.r, start) {
+                let (trigram, range) = entry?;
+                if !trigram.0.starts_with(&prefix) {
+                    break;
+                }
+
+                let section = section_between(range.start, range.end)?;
+                let mut doc_iter = self.posting_searcher_in(section)?.docs();
+                for doc_id in doc_iter.by_ref() {
+                    if doc_id >= self.header.num_docs {
+                        return Err(TridentError::corrupt(format!(
+                            "posting for {:?} has doc ID {} past the last doc",
+                            trigram, doc_id
+                        )));
+                    }
+                    docs.insert(doc_id);
+                }
+                if let Some(err) = doc_iter.take_error() {
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(docs)
+    }
+
+    // Returns an iterator over the candidate document IDs for a trigram query.
+    pub fn query_candidates<'a>(&'a self, query: &TrigramQuery) -> Box<dyn DocIterator + 'a> {
+        self.live(self.trigram_query_candidates(query))
+    }
+
+    fn trigram_query_candidates<'a>(&'a self, query: &TrigramQuery) -> Box<dyn DocIterator + 'a> {
+        match query {
+            TrigramQuery::All => Box::new(0..self.header.num_docs),
+            TrigramQuery::None => Box::new(std::iter::empty()),
+            TrigramQuery::Literal(literal) => self.literal_candidates(literal, Case::Sensitive),
+            TrigramQuery::And(subqueries) => Box::new(Intersection::new(
+                subqueries
+                    .iter()
+                    .map(|q| self.trigram_query_candidates(q))
+                    .collect(),
+            )),
+            TrigramQuery::Or(subqueries) => Box::new(Union::new(
+                subqueries
+                    .iter()
+                    .map(|q| self.trigram_query_candidates(q))
+                    .collect(),
+            )),
+        }
+    }
+
+    // Returns an iterator over the candidate document IDs for a boolean query. A negated subquery
+    // can only exclude docs if its candidates are exactly the docs that match it, so inexact
+    // negations do not narrow down the candidates.
+    pub fn boolean_candidates<'a>(&'a self, query: &Query) -> Box<dyn DocIterator + 'a> {
+        self.boolean_candidates_with_case(query, Case::Sensitive)
+    }
+
+    // Returns an iterator over the candidate document IDs for a boolean query, matching ASCII
+    // letters in either case if the search is case-insensitive.
+    pub fn boolean_candidates_with_case<'a>(
+        &'a self,
+        query: &Query,
+        case: Case,
+    ) -> Box<dyn DocIterator + 'a> {
+        self.live(self.boolean_query_candidates(query, case))
+    }
+
+    // Returns the candidates for a boolean query, including deleted docs. Deleted docs are only
+    // removed from the final stream, since removing them from a negated subquery would add them
+    // back.
+    fn boolean_query_candidates<'a>(
+        &'a self,
+        query: &Query,
+        case: Case,
+    ) -> Box<dyn DocIterator + 'a> {
+        match query {
+            Query::Literal(literal) => self.literal_candidates(literal, case),
+            Query::And(subqueries) => {
+                let (excluded, included): (Vec<&Query>, Vec<&Query>) = subqueries
+                    .iter()
+                    .partition(|q| matches!(q, Query::Not(q) if Self::is_exact(q)));
+
+                let docs: Box<dyn DocIterator + 'a> = match included.len() {
+                    0 => Box::new(0..self.header.num_docs),
+                    1 => self.boolean_query_candidates(included[0], case),
+                    _ => Box::new(Intersection::new(
+                        included
+                            .into_iter()
+                            .map(|q| self.boolean_query_candidates(q, case))
+                            .collect(),
+                    )),
+                };
+
+                excluded.into_iter().fold(docs, |docs, q| match q {
+                    Query::Not(q) => Box::new(Difference::new(
+                        docs,
+                        self.boolean_query_candidates(q, case),
+                    )),
+                    _ => unreachable!(),
+                })
+            }
+            Query::Or(subqueries) => Box::new(Union::new(
+                subqueries
+                    .iter()
+                    .map(|q| self.boolean_query_candidates(q, case))
+                    .collect(),
+            )),
+            Query::Not(q) if Self::is_exact(q) => Box::new(Difference::new(
+                Box::new(0..self.header.num_docs),
+                self.boolean_query_candidates(q, case),
+            )),
+            Query::Not(_) => Box::new(0..self.header.num_docs),
+        }
+    }
+
+    // Returns whether the candidates for a boolean query are exactly the docs that match it.
+    fn is_exact(query: &Query) -> bool {
+        match query {
+            // A literal of up to six bytes is always searched with a single (trigram, successor)
+            // pair or trigram prefix that covers the whole literal. 0xFF is excluded because it
+            // is used to pad the successors at the end of a doc.
+            Query::Literal(literal) => literal.len() <= 6 && !literal.contains(&0xFF),
+            Query::And(subqueries) | Query::Or(subqueries) => subqueries.iter().all(Self::is_exact),
+            Query::Not(q) => Self::is_exact(q),
+        }
+    }
+
+    // Returns an iterator over the candidate document IDs for a regex. The candidates still need
+    // to be verified against their content, which `Index::search` does.
+    pub fn regex_candidates<'a>(&'a self, query: &RegexQuery) -> Box<dyn DocIterator + 'a> {
+        self.query_candidates(query.trigram_query())
+    }
+
+    // Returns an iterator over the docs that contain the leading trigram of the given pair
+    // followed by the (possibly partial) successor in the remaining bytes, or None if the
+    // leading trigram does not exist in the index. The leading trigram's posting is found in the
+    // sections looked up by the plan. A case-insensitive search unions the docs of every case
+    // variant of the pair.
+    fn pair_candidates<'a>(
+        &'a self,
+        pair: &[u8],
+        case: Case,
+        sections: &FxHashMap<Trigram, TrigramPostingSection>,
+    ) -> Option<Box<dyn DocIterator + 'a>> {
+        if case == Case::Sensitive {
+            return self.exact_pair_candidates(pair, sections);
+        }
+
+        let doc_iters = case_variants(pair, case)
+            .iter()
+            .filter_map(|v| self.exact_pair_candidates(v, sections))
+            .collect::<Vec<_>>();
+        match doc_iters.len() {
+            0 => None,
+            1 => doc_iters.into_iter().next(),
+            _ => Some(Box::new(Union::new(doc_iters))),
+        }
+    }
+
+    // An error reading the posting is returned by the candidates' `take_error`
+    fn exact_pair_candidates<'a>(
+        &'a self,
+        pair: &[u8],
+        sections: &FxHashMap<Trigram, TrigramPostingSection>,
+    ) -> Option<Box<dyn DocIterator + 'a>> {
+        let (leading_trigram, rest) = pair.split_at(3);
+        let section = *sections.get(&Trigram::try_from(leading_trigram).unwrap())?;
+        match self.posting_searcher_in(section) {
+            Ok(searcher) => Some(searcher.search(rest)),
+            Err(err) => Some(Box::new(Failed::new(err))),
+        }
+    }
+
+    // Returns a searcher for the posting at a section of the postings
+    fn posting_searcher_in(
+        &self,
+        trigram_section: TrigramPostingSection,
+    ) -> Result<PostingSearcher<'_, R>> {
+        let posting_header = {
+            let absolute_section = self.header.trigram_postings.narrow(trigram_section)?;
+            let mut reader = reader_in(&self.r, absolute_section);
+            PostingHeader::read_from(&mut reader)?
+        };
+
+        PostingSearcher::new(
+            self.header.trigram_postings,
+            trigram_section,
+            posting_header,
+            self.header.matrix_layout,
+            &self.r,
+        )
+    }
+
+    // Returns every trigram in the index in order, along with the docs that contain it and the
+    // successors of the trigram in each doc. Postings are decoded one at a time.
+    pub fn postings(&self) -> impl Iterator<Item = Result<(Trigram, DecodedPosting)>> + '_ {
+        self.dictionary.entries(&self.r, 0).map(|entry| {
+            let (trigram, range) = entry?;
+            let section = section_between(range.start, range.end)?;
+            let docs = self.posting_searcher_in(section)?.decode()?;
+            if let Some(&(doc_id, _)) = docs.iter().find(|(d, _)| *d >= self.header.num_docs) {
+                return Err(TridentError::DocOutOfRange(doc_id));
+            }
+            Ok((trigram, docs))
+        })
+    }
+
+    // Checks the index for corruption: that every section is within the file and matches its
+    // checksum, and that the header of every posting is for the right trigram and fits within
+    // the posting. Returns a description of each problem found.
+    pub fn verify(&self) -> Result<Vec<String>> {
+        let mut problems = Vec::new();
+
+        let data_len = self.r.len()? - IndexHeader::SIZE_BYTES as u64;
+        let checksums = self.header.checksums.iter();
+        for ((name, section), &expected) in self.header.sections().into_iter().zip(checksums) {
+            match section.offset.checked_add(section.len) {
+                Some(end) if end <= data_len => {}
+                _ => {
+                    problems.push(format!(
+                        "{} section at {}+{} is past the end of the data at {}",
+                        name, section.offset, section.len, data_len
+                    ));
+                    continue;
+                }
+            }
+            let actual = section_checksum(&self.r, section)?;
+            if actual != expected {
+                problems.push(format!(
+                    "{} section has checksum {:08x}, expected {:08x}",
+                    name, actual, expected
+                ));
+            }
+        }
+
+        let postings = self.header.trigram_postings;
+        let (mut last, mut sorted, mut present) = (None, true, true);
+        for (idx, entry) in self.dictionary.entries(&self.r, 0).enumerate() {
+            let (trigram, Range { start, end }) = entry?;
+            sorted &= last < Some(trigram);
+            last = Some(trigram);
+            if self.dictionary.has_presence_table() {
+                let found = self.dictionary.find(&self.r, trigram);
+                present &= matches!(found, Ok(Some(i)) if i == idx as u64);
+            }
+            if end < start || end > postings.len {
+                problems.push(format!(
+                    "posting for {:?} at {}..{} is outside the postings section",
+                    trigram, start, end
+                ));
+                continue;
+            }
+            let section = Section::new(start, end - start);
+            if section.len < PostingHeader::SIZE_BYTES as u64 {
+                problems.push(format!(
+                    "posting for {:?} is too short for its header",
+                    trigram
+                ));
+                continue;
+            }
+
+            let header =
+                PostingHeader::read_from(&mut reader_in(&self.r, postings.narrow(section)?))?;
+            if header.trigram != trigram {
+                problems.push(format!(
+                    "posting for {:?} has a header for {:?}",
+                    trigram, header.trigram
+                ));
+            }
+            let docs = header.docs_section();
+            if docs.offset + docs.len != section.len {
+                problems.push(format!(
+                    "posting for {:?} is {} bytes, but its header describes {} bytes",
+                    trigram,
+                    section.len,
+                    docs.offset + docs.len
+                ));
+            }
+        }
+        if !sorted {
+            problems.push("unique trigrams are not sorted".to_string());
+        }
+        if !present {
+            problems.push("trigram presence table does not match the unique trigrams".to_string());
+        }
+        Ok(problems)
+    }
+}
+
+struct PostingSearcher<'a, R> {
+    header: PostingHeader,
+
+    // The location of each part of the posting in the file
+    successors_section: Section,
+    matrix_rows_section: Section,
+    matrix_section: Section,
+    docs_section: Section,
+
+    matrix_layout: MatrixLayout,
+    r: &'a R,
+}
+
+type PostingReader<'a, R> = BufReader<Cursor<&'a R>>;
+
+// The docs of a posting, each with the successors of the trigram in it
+type DecodedPosting = Vec<(DocID, FxHashSet<Trigram>)>;
+
+impl<'a, R: ReadAt + Len> PostingSearcher<'a, R> {
+    // Fails if any part of the posting described by the header is outside the posting
+    pub fn new(
+        postings_section: TrigramPostingsSection,
+        posting_section: TrigramPostingSection,
+        header: PostingHeader,
+        matrix_layout: MatrixLayout,
+        r: &'a R,
+    ) -> Result<Self> {
+        let section = |s| postings_section.narrow(posting_section.narrow(s)?);
+        Ok(Self {
+            successors_section: section(header.successors_section())?,
+            matrix_rows_section: section(header.matrix_rows_section())?,
+            matrix_section: section(header.matrix_section())?,
+            docs_section: section(header.docs_section())?,
+            header,
+            matrix_layout,
+            r,
+        })
+    }
+
+    fn successors(&self) -> U32DeltaDecompressor<PostingReader<'a, R>> {
+        U32DeltaDecompressor::new(
+            reader_in(self.r, self.successors_section),
+            self.header.successors_count as usize,
+        )
+    }
+
+    // Returns the entries of the matrix as (row, column), where rows are docs or successors
+    // depending on the layout
+    fn matrix(&self) -> MatrixDecompressor<PostingReader<'a, R>> {
+        let rows = match self.matrix_layout {
+            MatrixLayout::DocMajor => self.header.docs_count,
+            MatrixLayout::SuccessorMajor => self.header.successors_count,
+        };
+
+        MatrixDecompressor::new(
+            reader_in(self.r, self.matrix_rows_section),
+            rows as usize,
+            reader_in(self.r, self.matrix_section),
+            self.header.matrix_count as usize,
+        )
+    }
+
+    // Returns the sorted local indexes of the docs that contain any of a range of successors
+    fn successor_docs(&self, successors: Range<LocalSuccessorIdx>) -> Box<dyn DocIterator + 'a> {
+        let mut matrix = self.matrix();
+        match self.matrix_layout {
+            // Every doc's successors are interleaved, so the whole matrix has to be scanned
+            MatrixLayout::DocMajor => {
+                Box::new(SuccessorDocs::new(matrix, self.matrix_layout, successors))
+            }
+
+            // The docs of each successor are contiguous, so skip straight to the first one
+            MatrixLayout::SuccessorMajor => {
+                matrix.skip_to_row(successors.start);
+                let multiple = successors.len() > 1;
+                let mut docs = SuccessorDocs::new(matrix, self.matrix_layout, successors);
+                if !multiple {
+                    return Box::new(docs);
+                }
+                let sorted = docs.by_ref().sorted().dedup().collect::<Vec<_>>();
+                match docs.take_error() {
+                    Some(err) => Box::new(Failed::new(err)),
+                    None => Box::new(sorted.into_iter()),
+                }
+            }
+        }
+    }
+
+    fn docs(&self) -> U32DeltaDecompressor<PostingReader<'a, R>> {
+        U32DeltaDecompressor::new(
+            reader_in(self.r, self.docs_section),
+            self.header.docs_count as usize,
+        )
+    }
+
+    // Decodes the whole posting into each doc and the successors of the trigram in it
+    fn decode(&self) -> Result<DecodedPosting> {
+        let mut successors_iter = self.successors();
+        let successors = successors_iter
+            .by_ref()
+            .map(Trigram::from)
+            .collect::<Vec<_>>();
+        if let Some(err) = successors_iter.take_error() {
+            return Err(err.into());
+        }
+
+        let mut docs_iter = self.docs();
+        let mut docs = docs_iter
+            .by_ref()
+            .map(|doc_id| (doc_id, FxHashSet::default()))
+            .collect::<Vec<_>>();
+        if let Some(err) = docs_iter.take_error() {
+            return Err(err.into());
+        }
+
+        let mut matrix = self.matrix();
+        for (row, column) in matrix.by_ref() {
+            let (local_doc_id, local_successor_id) = match self.matrix_layout {
+                MatrixLayout::DocMajor => (row, column),
+                MatrixLayout::SuccessorMajor => (column, row),
+            };
+            match (
+                docs.get_mut(local_doc_id as usize),
+                successors.get(local_successor_id as usize),
+            ) {
+                (Some((_, doc_successors)), Some(&successor)) => {
+                    doc_successors.insert(successor);
+                }
+                _ => {
+                    return Err(TridentError::corrupt(format!(
+                        "successor matrix entry ({}, {}) is out of range",
+                        row, column
+                    )))
+                }
+            }
+        }
+        match matrix.take_error() {
+            Some(err) => Err(err.into()),
+            None => Ok(docs),
+        }
+    }
+
+    fn search(self, remainder: &[u8]) -> Box<dyn DocIterator + 'a> {
+        match remainder.len() {
+            // In the case where we have no extra successor information, we can just return the
+            // list of unique doc IDs for the posting.
+            0 => Box::new(self.docs()),
+
+            // In the case where we do not have a full successor trigram, we find the range of
+            // unique successor trigrams that share a prefix, then use that to filter the
+            // successors matrix.
+            1..=2 => {
+                let mut target_prefix = 0u32;
+                for b in remainder {
+                    target_prefix = target_prefix << 8;
+                    target_prefix += *b as u32;
+                }
+                let shift = (3 - remainder.len()) * 8;
+
+                // The successors are sorted, so the range starts at the first successor with the
+                // prefix and ends at the first successor past it. Only the blocks that hold those
+                // successors are decoded.
+                let (low, high) = (target_prefix << shift, (target_prefix + 1) << shift);
+                let mut successors = self.successors();
+                let (start, end) = match successors.advance_to(low) {
+                    Some(successor) if successor < high => {
+                        let start = successors.yielded() as u32 - 1;
+                        match successors.advance_to(high) {
+                            Some(_) => (start, successors.yielded() as u32 - 1),
+                            None => (start, successors.yielded() as u32),
+                        }
+                    }
+                    // The range of matching successors is empty, so return early with no matches.
+                    _ => return empty_or_failed(successors.take_error()),
+                };
+                if let Some(err) = successors.take_error() {
+                    return Box::new(Failed::new(err.into()));
+                }
+
+                Box::new(DocIDMapper::new(
+                    self.docs(),
+                    self.successor_docs(start..end),
+                ))
+            }
+
+            // In the case where we have at least a full trigram, we filter to only successor
+            // trigrams that exactly match that.
+            _ => {
+                let target_successor_id = TrigramID::from(Trigram::try_from(remainder).unwrap());
+                let mut successors = self.successors();
+                let target_local_successor_id = match successors.advance_to(target_successor_id) {
+                    Some(id) if id == target_successor_id => successors.yielded() as u32 - 1,
+                    _ => return empty_or_failed(successors.take_error()),
+                };
+
+                Box::new(DocIDMapper::new(
+                    self.docs(),
+                    self.successor_docs(target_local_successor_id..target_local_successor_id + 1),
+                ))
+            }
+        }
+    }
+}
+
+// The order of the coordinates in the successor matrix of each posting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatrixLayout {
+    // Coordinates are `local_doc * successors_count + local_successor`, so each doc's successors
+    // are contiguous
+    DocMajor = 0,
+
+    // Coordinates are `local_successor * docs_count + local_doc`, so each successor's docs are
+    // contiguous
+    SuccessorMajor = 1,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexHeader {
+    pub num_docs: u32,
+    pub matrix_layout: MatrixLayout,
+    pub trigram_postings: TrigramPostingsSection,
+    pub unique_trigrams: UniqueTrigramsSection,
+    pub trigram_posting_ends: TrigramPostingEndsSection,
+
+    // The presence table of the trigram dictionary, which is empty if the index doesn't have one
+    pub trigram_presence: TrigramPresenceSection,
+    pub contents: ContentsSection,
+    pub content_ends: ContentEndsSection,
+    pub doc_names: DocNamesSection,
+    pub doc_name_block_ends: DocNameBlockEndsSection,
+    pub metadata: MetadataSection,
+
+    // The checksum of each section, in the order of `sections`
+    pub checksums: [u32; IndexHeader::NUM_SECTIONS],
+}
+
+// The last bytes of every index file
+pub const MAGIC: [u8; 4] = *b"TRDX";
+
+// The version of the index format that this build writes. Readers reject other versions, so any
+// change to the layout of the file must bump it.
+pub const FORMAT_VERSION: u32 = 3;
+
+impl IndexHeader {
+    // TODO: calculate this from member sizes
+    const SIZE_BYTES: usize = 196;
+
+    const NUM_SECTIONS: usize = 9;
+
+    // The format version followed by the magic, which end the header
+    const TRAILER_BYTES: usize = 8;
+
+    // Checks that the trailer identifies an index in a format this build can read
+    fn read_trailer<R: Read>(r: &mut R) -> Result<()> {
+        let version = r.read_u32::<LittleEndian>()?;
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(TridentError::NotAnIndex);
+        }
+        if version != FORMAT_VERSION {
+            return Err(TridentError::UnsupportedVersion(version));
+        }
+        Ok(())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<Self> {
+        let mut header = IndexHeader {
+            num_docs: r.read_u32::<LittleEndian>()?,
+            matrix_layout: match r.read_u32::<LittleEndian>()? {
+                0 => MatrixLayout::DocMajor,
+                1 => MatrixLayout::SuccessorMajor,
+                n => {
+                    return Err(TridentError::corrupt(format!(
+                        "unknown matrix layout {}",
+                        n
+                    )))
+                }
+            },
+            trigram_postings: TrigramPostingsSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            unique_trigrams: UniqueTrigramsSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            trigram_posting_ends: TrigramPostingEndsSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            trigram_presence: TrigramPresenceSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            contents: ContentsSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            content_ends: ContentEndsSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            doc_names: DocNamesSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            doc_name_block_ends: DocNameBlockEndsSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            metadata: MetadataSection::new(
+                r.read_u64::<LittleEndian>()?,
+                r.read_u64::<LittleEndian>()?,
+            ),
+            checksums: [0; Self::NUM_SECTIONS],
+        };
+        r.read_u32_into::<LittleEndian>(&mut header.checksums)?;
+        Self::read_trailer(r)?;
+
+        let whole_entries = header.unique_trigrams.len.is_multiple_of(3)
+            && header.trigram_posting_ends.len.is_multiple_of(8)
+            && header.content_ends.len.is_multiple_of(8)
+            && header.doc_name_block_ends.len.is_multiple_of(8);
+        if !whole_entries {
+            return Err(TridentError::corrupt(
+                "a section does not hold a whole number of entries",
+            ));
+        }
+        if header.unique_trigrams.len / 3 != header.trigram_posting_ends.len / 8 {
+            return Err(TridentError::corrupt(
+                "unique trigrams and posting ends have different lengths",
+            ));
+        }
+        if header.trigram_presence.len != 0 && header.trigram_presence.len != PRESENCE_TABLE_BYTES {
+            return Err(TridentError::corrupt(format!(
+                "trigram presence table is {} bytes, expected {}",
+                header.trigram_presence.len, PRESENCE_TABLE_BYTES
+            )));
+        }
+
+        // The number of docs sizes the bitmaps of a search, so check it against the sections that
+        // have an entry per doc before trusting it
+        let name_blocks = (header.num_docs as u64).div_ceil(FRONT_CODED_BLOCK_LEN as u64);
+        let content_docs = header.content_ends.len / 8;
+        if header.doc_name_block_ends.len / 8 != name_blocks
+            || (content_docs != 0 && content_docs != header.num_docs as u64)
+        {
+            return Err(TridentError::corrupt(format!(
+                "doc sections do not match the {} docs in the index",
+                header.num_docs
+            )));
+        }
+        Ok(header)
+    }
+
+    // Returns the name and location of every section of the index, in the order that they are
+    // written. A new section must be added here so that it gets a checksum.
+    pub fn sections(&self) -> [(&'static str, Section); Self::NUM_SECTIONS] {
+        [
+            ("trigram postings", self.trigram_postings),
+            ("unique trigrams", self.unique_trigrams),
+            ("trigram posting ends", self.trigram_posting_ends),
+            ("trigram presence", self.trigram_presence),
+            ("contents", self.contents),
+            ("content ends", self.content_ends),
+            ("doc names", self.doc_names),
+            ("doc name block ends", self.doc_name_block_ends),
+            ("metadata", self.metadata),
+        ]
+    }
+}
+
+impl StreamWriter for IndexHeader {
+    fn write_to<W: Write>(&self, w: &mut W) -> anyhow::Result<usize> {
+        w.write_u32::<LittleEndian>(self.num_docs)?;
+        w.write_u32::<LittleEndian>(self.matrix_layout as u32)?;
+        let mut n = 8;
+        n += self.trigram_postings.write_to(w)?;
+        n += self.unique_trigrams.write_to(w)?;
+        n += self.trigram_posting_ends.write_to(w)?;
+        n += self.trigram_presence.write_to(w)?;
+        n += self.contents.write_to(w)?;
+        n += self.content_ends.write_to(w)?;
+        n += self.doc_names.write_to(w)?;
+        n += self.doc_name_block_ends.write_to(w)?;
+        n += self.metadata.write_to(w)?;
+        for checksum in self.checksums {
+            w.write_u32::<LittleEndian>(checksum)?;
+            n += 4;
+        }
+        w.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+        w.write_all(&MAGIC)?;
+        n += Self::TRAILER_BYTES;
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PostingHeader {
+    pub trigram: Trigram,
+    pub successors_count: u32,
+    pub successors_bytes: u32,
+    pub matrix_count: u32,
+    pub matrix_rows_bytes: u32,
+    pub matrix_bytes: u32,
+    pub docs_count: u32,
+    pub docs_bytes: u32,
+}
+
+impl PostingHeader {
+    const SIZE_BYTES: usize = 3 + 4 * 7;
+
+    fn read_from<R: Read>(r: &mut R) -> Result<Self> {
+        let mut buf = [0u8; 3];
+        r.read_exact(&mut buf[..])?;
+        Ok(Self {
+            trigram: Trigram(buf),
+            successors_count: r.read_u32::<LittleEndian>()?,
+            successors_bytes: r.read_u32::<LittleEndian>()?,
+            matrix_count: r.read_u32::<LittleEndian>()?,
+            matrix_rows_bytes: r.read_u32::<LittleEndian>()?,
+            matrix_bytes: r.read_u32::<LittleEndian>()?,
+            docs_count: r.read_u32::<LittleEndian>()?,
+            docs_bytes: r.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    // TODO make these less error prone
+    fn successors_section(&self) -> SuccessorsSection {
+        Section::new(Self::SIZE_BYTES as u64, self.successors_bytes as u64)
+    }
+
+    fn matrix_rows_section(&self) -> MatrixSection {
+        Section::new(
+            Self::SIZE_BYTES as u64 + self.successors_bytes as u64,
+            self.matrix_rows_bytes as u64,
+        )
+    }
+
+    fn matrix_section(&self) -> MatrixSection {
+        Section::new(
+            Self::SIZE_BYTES as u64 + self.successors_bytes as u64 + self.matrix_rows_bytes as u64,
+            self.matrix_bytes as u64,
+        )
+    }
+
+    fn docs_section(&self) -> DocsSection {
+        Section::new(
+            Self::SIZE_BYTES as u64
+                + self.successors_bytes as u64
+                + self.matrix_rows_bytes as u64
+                + self.matrix_bytes as u64,
+            self.docs_bytes as u64,
+        )
+    }
+}
+
+impl StreamWriter for PostingHeader {
+    fn write_to<W: Write>(&self, w: &mut W) -> anyhow::Result<usize> {
+        w.write_all(&<[u8; 3]>::from(self.trigram))?;
+        w.write_u32::<LittleEndian>(self.successors_count)?;
+        w.write_u32::<LittleEndian>(self.successors_bytes)?;
+        w.write_u32::<LittleEndian>(self.matrix_count)?;
+        w.write_u32::<LittleEndian>(self.matrix_rows_bytes)?;
+        w.write_u32::<LittleEndian>(self.matrix_bytes)?;
+        w.write_u32::<LittleEndian>(self.docs_count)?;
+        w.write_u32::<LittleEndian>(self.docs_bytes)?;
+        Ok(7 * std::mem::size_of::<u32>() + 3)
+    }
+}
+
+// Named types for each unique type of section
+type UniqueTrigramsSection = Section;
+type TrigramPostingEndsSection = Section;
+type TrigramPresenceSection = Section;
+type ContentsSection = Section;
+type ContentEndsSection = Section;
+type DocNamesSection = Section;
+type DocNameBlockEndsSection = Section;
+type MetadataSection = Section;
+type TrigramPostingsSection = Section;
+type TrigramPostingSection = Section<TrigramPostingsSection>;
+type SuccessorsSection = Section<TrigramPostingSection>;
+type DocsSection = Section<TrigramPostingSection>;
+type MatrixSection = Section<TrigramPostingSection>;
+
+// A sorted iterator over doc IDs that can skip ahead to a target doc ID. An iterator that fails
+// to read its doc IDs ends early, so once it is exhausted, `take_error` should be checked to tell
+// whether it really had no more docs.
+pub trait DocIterator: Iterator<Item = DocID> {
+    // Advances to the first remaining doc ID that is at least `target` and returns it
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        loop {
+            let doc_id = self.next()?;
+            if doc_id >= target {
+                return Some(doc_id);
+            }
+        }
+    }
+
+    // Returns the error that ended the iterator early, if any
+    fn take_error(&mut self) -> Option<TridentError> {
+        None
+    }
+}
+
+impl<'a> DocIterator for Box<dyn DocIterator + 'a> {
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        (**self).advance_to(target)
+    }
+
+    fn take_error(&mut self) -> Option<TridentError> {
+        (**self).take_error()
+    }
+}
+
+impl DocIterator for std::ops::Range<DocID> {
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        self.start = self.start.max(target);
+        self.next()
+    }
+}
+
+impl DocIterator for std::iter::Empty<DocID> {}
+
+impl DocIterator for std::vec::IntoIter<DocID> {}
+
+impl<R: Read + Seek> DocIterator for U32DeltaDecompressor<R> {
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        U32DeltaDecompressor::advance_to(self, target)
+    }
+
+    fn take_error(&mut self) -> Option<TridentError> {
+        U32DeltaDecompressor::take_error(self).map(TridentError::from)
+    }
+}
+
+// An iterator that yields no docs because reading them failed, and returns the error from
+// `take_error`
+pub struct Failed(Option<TridentError>);
+
+impl Failed {
+    pub fn new(err: TridentError) -> Self {
+        Self(Some(err))
+    }
+}
+
+impl Iterator for Failed {
+    type Item = DocID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        None
+    }
+}
+
+impl DocIterator for Failed {
+    fn take_error(&mut self) -> Option<TridentError> {
+        self.0.take()
+    }
+}
+
+// Returns an iterator over no docs, or one that fails with the error that cut short the read of a
+// posting
+fn empty_or_failed<'a>(error: Option<io::Error>) -> Box<dyn DocIterator + 'a> {
+    match error {
+        Some(err) => Box::new(Failed::new(err.into())),
+        None => Box::new(std::iter::empty()),
+    }
+}
+
+// Iterates over the local indexes of the docs in a successor matrix that contain any of a range
+// of successors. The indexes are sorted for a doc-major matrix, and for a successor-major matrix
+// that is read from the start of a single successor's row.
+struct SuccessorDocs<R: Read> {
+    matrix: MatrixDecompressor<R>,
+    layout: MatrixLayout,
+    successors: Range<LocalSuccessorIdx>,
+    last: Option<LocalDocIdx>,
+}
+
+impl<R: Read> SuccessorDocs<R> {
+    fn new(
+        matrix: MatrixDecompressor<R>,
+        layout: MatrixLayout,
+        successors: Range<LocalSuccessorIdx>,
+    ) -> Self {
+        Self {
+            matrix,
+            layout,
+            successors,
+            last: None,
+        }
+    }
+}
+
+impl<R: Read + Seek> Iterator for SuccessorDocs<R> {
+    type Item = LocalDocIdx;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (local_doc_id, local_successor_id) = match (self.layout, self.matrix.next()?) {
+                (MatrixLayout::DocMajor, (row, column)) => (row, column),
+                (MatrixLayout::SuccessorMajor, (row, column)) => {
+                    // The rows are successors, so the range ends with the first row past it
+                    if row >= self.successors.end {
+                        return None;
+                    }
+                    (column, row)
+                }
+            };
+            if self.successors.contains(&local_successor_id) && self.last != Some(local_doc_id) {
+                self.last = Some(local_doc_id);
+                return Some(local_doc_id);
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> DocIterator for SuccessorDocs<R> {
+    fn take_error(&mut self) -> Option<TridentError> {
+        self.matrix.take_error().map(TridentError::from)
+    }
+}
+
+// Maps the sorted local indexes of docs in a posting to their doc IDs
+struct DocIDMapper<DI, LDI> {
+    doc_id_iterator: DI,
+    local_doc_iterator: LDI,
+
+    // The local index of the next doc ID in doc_id_iterator
+    position: usize,
+
+    // The error that ended the iterator early, if any
+    error: Option<TridentError>,
+}
+
+impl<DI, LDI> DocIDMapper<DI, LDI>
+where
+    DI: DocIterator,
+    LDI: DocIterator,
+{
+    pub fn new(doc_id_iterator: DI, local_doc_iterator: LDI) -> Self {
+        Self {
+            doc_id_iterator,
+            local_doc_iterator,
+            position: 0,
+            error: None,
+        }
+    }
+}
+
+impl<DI, LDI> Iterator for DocIDMapper<DI, LDI>
+where
+    DI: DocIterator,
+    LDI: DocIterator,
+{
+    type Item = DocID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        let ldi = self.local_doc_iterator.next()? as usize;
+        let Some(skipped) = ldi.checked_sub(self.position) else {
+            self.error = Some(TridentError::corrupt("local doc indexes are not sorted"));
+            return None;
+        };
+        // The doc IDs are delta-compressed, so `nth` skips whole blocks without decoding them
+        match self.doc_id_iterator.nth(skipped) {
+            Some(doc_id) => {
+                self.position = ldi + 1;
+                Some(doc_id)
+            }
+            None => {
+                self.error = Some(self.doc_id_iterator.take_error().unwrap_or_else(|| {
+                    TridentError::corrupt(format!("local doc index {} is out of range", ldi))
+                }));
+                None
+            }
+        }
+    }
+}
+
+impl<DI, LDI> DocIterator for DocIDMapper<DI, LDI>
+where
+    DI: DocIterator,
+    LDI: DocIterator,
+{
+    fn take_error(&mut self) -> Option<TridentError> {
+        self.error
+            .take()
+            .or_else(|| self.local_doc_iterator.take_error())
+    }
+}
+
+// An iterator over the doc IDs that are yielded by every one of a set of sorted doc ID iterators.
+struct Intersection<'a> {
+    doc_iters: Vec<Box<dyn DocIterator + 'a>>,
+}
+
+impl<'a> Intersection<'a> {
+    pub fn new(doc_iters: Vec<Box<dyn DocIterator + 'a>>) -> Self {
+        Self { doc_iters }
+    }
+
+    // Returns the first doc ID that is at least the target, which the first iterator is already
+    // positioned at.
+    fn converge(&mut self, mut target: DocID) -> Option<DocID> {
+        let n = self.doc_iters.len();
+
+        // Cycle through the iterators, advancing each one to the current target. Whenever an
+        // iterator overshoots the target, its value becomes the new target. Once every iterator
+        // agrees on the target in a row, it is in the intersection.
+        let mut agreeing = 1;
+        let mut i = 1;
+        while agreeing < n {
+            let doc_id = self.doc_iters[i % n].advance_to(target)?;
+            if doc_id == target {
+                agreeing += 1;
+            } else {
+                target = doc_id;
+                agreeing = 1;
+            }
+            i += 1;
+        }
+        Some(target)
+    }
+}
+
+impl<'a> Iterator for Intersection<'a> {
+    type Item = DocID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let target = self.doc_iters.first_mut()?.next()?;
+        self.converge(target)
+    }
+}
+
+impl<'a> DocIterator for Intersection<'a> {
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        let target = self.doc_iters.first_mut()?.advance_to(target)?;
+        self.converge(target)
+    }
+
+    fn take_error(&mut self) -> Option<TridentError> {
+        self.doc_iters.iter_mut().find_map(|d| d.take_error())
+    }
+}
+
+// An iterator over the doc IDs that are yielded by any of a set of sorted doc ID iterators.
+struct Union<'a> {
+    doc_iters: Vec<Box<dyn DocIterator + 'a>>,
+
+    // The next doc ID of each iterator, or None if it is exhausted
+    heads: Vec<Option<DocID>>,
+}
+
+impl<'a> Union<'a> {
+    pub fn new(mut doc_iters: Vec<Box<dyn DocIterator + 'a>>) -> Self {
+        let heads = doc_iters.iter_mut().map(|d| d.next()).collect();
+        Self { doc_iters, heads }
+    }
+}
+
+impl<'a> Iterator for Union<'a> {
+    type Item = DocID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let min = self.heads.iter().flatten().min().copied()?;
+        for (head, doc_iter) in self.heads.iter_mut().zip(self.doc_iters.iter_mut()) {
+            if *head == Some(min) {
+                *head = doc_iter.next();
+            }
+        }
+        Some(min)
+    }
+}
+
+impl<'a> DocIterator for Union<'a> {
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        for (head, doc_iter) in self.heads.iter_mut().zip(self.doc_iters.iter_mut()) {
+            if matches!(head, Some(h) if *h < target) {
+                *head = doc_iter.advance_to(target);
+            }
+        }
+        self.next()
+    }
+
+    fn take_error(&mut self) -> Option<TridentError> {
+        self.doc_iters.iter_mut().find_map(|d| d.take_error())
+    }
+}
+
+// An iterator over the doc IDs yielded by one sorted doc ID iterator but not by another.
+struct Difference<'a> {
+    doc_iter: Box<dyn DocIterator + 'a>,
+    excluded: Box<dyn DocIterator + 'a>,
+
+    // The next doc ID of the excluded iterator, or None if it is exhausted
+    next_excluded: Option<DocID>,
+}
+
+impl<'a> Difference<'a> {
+    pub fn new(
+        doc_iter: Box<dyn DocIterator + 'a>,
+        mut excluded: Box<dyn DocIterator + 'a>,
+    ) -> Self {
+        let next_excluded = excluded.next();
+        Self {
+            doc_iter,
+            excluded,
+            next_excluded,
+        }
+    }
+
+    // Returns the first doc ID starting at the given one that is not excluded
+    fn skip_excluded(&mut self, mut doc_id: DocID) -> Option<DocID> {
+        loop {
+            if matches!(self.next_excluded, Some(e) if e < doc_id) {
+                self.next_excluded = self.excluded.advance_to(doc_id);
+            }
+            if self.next_excluded != Some(doc_id) {
+                return Some(doc_id);
+            }
+            doc_id = self.doc_iter.next()?;
+        }
+    }
+}
+
+impl<'a> Iterator for Difference<'a> {
+    type Item = DocID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let doc_id = self.doc_iter.next()?;
+        self.skip_excluded(doc_id)
+    }
+}
+
+impl<'a> DocIterator for Difference<'a> {
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        let doc_id = self.doc_iter.advance_to(target)?;
+        self.skip_excluded(doc_id)
+    }
+
+    fn take_error(&mut self) -> Option<TridentError> {
+        self.doc_iter
+            .take_error()
+            .or_else(|| self.excluded.take_error())
+    }
+}
+
+// Concatenates the doc IDs of several indexes, offsetting the doc IDs of each index to follow
+// those of the indexes before it
+pub(crate) struct Concatenation<'a> {
+    // Each remaining iterator and the offset of its doc IDs, in order of offset
+    doc_iters: VecDeque<(DocID, Box<dyn DocIterator + 'a>)>,
+
+    // The error that ended the iterator early, if any
+    error: Option<TridentError>,
+}
+
+impl<'a> Concatenation<'a> {
+    pub fn new(doc_iters: Vec<(DocID, Box<dyn DocIterator + 'a>)>) -> Self {
+        Self {
+            doc_iters: doc_iters.into(),
+            error: None,
+        }
+    }
+
+    // Drops the first iterator once it is exhausted, or every iterator if it failed
+    fn pop_front(&mut self) {
+        if let Some((_, mut doc_iter)) = self.doc_iters.pop_front() {
+            if let Some(err) = doc_iter.take_error() {
+                self.error = Some(err);
+                self.doc_iters.clear();
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Concatenation<'a> {
+    type Item = DocID;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((offset, doc_iter)) = self.doc_iters.front_mut() {
+            match doc_iter.next() {
+                Some(doc_id) => return Some(doc_id + *offset),
+                None => self.pop_front(),
+            };
+        }
+        None
+    }
+}
+
+impl<'a> DocIterator for Concatenation<'a> {
+    // Skips every iterator whose doc IDs all come before the target
+    fn advance_to(&mut self, target: DocID) -> Option<DocID> {
+        while self.doc_iters.len() > 1 && self.doc_iters[1].0 <= target {
+            self.doc_iters.pop_front();
+        }
+        let (offset, doc_iter) = self.doc_iters.front_mut()?;
+        match doc_iter.advance_to(target.saturating_sub(*offset)) {
+            Some(doc_id) => Some(doc_id + *offset),
+            None => {
+                self.pop_front();
+                self.next()
+            }
+        }
+    }
+
+    fn take_error(&mut self) -> Option<TridentError> {
+        self.error.take()
+    }
+}
+
+// Returns every combination of ASCII case of the letters in the bytes if the search is
+// case-insensitive, or just the bytes if it is case-sensitive.
+fn case_variants(bytes: &[u8], case: Case) -> Vec<Vec<u8>> {
+    let mut variants = vec![Vec::with_capacity(bytes.len())];
+    for &b in bytes {
+        if case == Case::Insensitive && b.is_ascii_alphabetic() {
+            variants = variants
+                .into_iter()
+                .flat_map(|v| {
+                    [b.to_ascii_lowercase(), b.to_ascii_uppercase()].map(|c| {
+                        let mut v = v.clone();
+                        v.push(c);
+                        v
+                    })
+                })
+                .collect();
+        } else {
+            variants.iter_mut().for_each(|v| v.push(b));
+        }
+    }
+    variants
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String> {
+    let len: u64 = r.read_varint()?;
+    let buf = read_bytes(r, len)?;
+    String::from_utf8(buf).map_err(|_| TridentError::corrupt("string is not UTF-8"))
+}
+
+// Reads a number of bytes given by the index, which is not trusted enough to allocate up front
+fn read_bytes<R: Read>(r: &mut R, len: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(TridentError::corrupt(format!(
+            "expected {} bytes, found {}",
+            len,
+            buf.len()
+        )));
+    }
+    Ok(buf)
+}
+
+// Decodes a delta-compressed list of docs, checking that every doc is in the index
+fn decode_docs(buf: Vec<u8>, count: usize, num_docs: u32) -> Result<Vec<DocID>> {
+    let mut decompressor = U32DeltaDecompressor::new(std::io::Cursor::new(buf), count);
+    let docs = decompressor.by_ref().collect::<Vec<_>>();
+    if let Some(err) = decompressor.take_error() {
+        return Err(err.into());
+    }
+    match docs.iter().find(|&&doc_id| doc_id >= num_docs) {
+        Some(doc_id) => Err(TridentError::DocOutOfRange(*doc_id)),
+        None => Ok(docs),
+    }
+}
+
+// Returns the section between two offsets that were read from the index
+fn section_between<P: SectionType>(start: u64, end: u64) -> Result<Section<P>> {
+    match end.checked_sub(start) {
+        Some(len) => Ok(Section::new(start, len)),
+        None => Err(TridentError::corrupt(format!(
+            "section ends at {} before it starts at {}",
+            end, start
+        ))),
+    }
+}
+
+fn reader_in<R: ReadAt>(r: &R, section: Section) -> BufReader<Cursor<&R>> {
+    let cursor = Cursor::new_in(r, section);
+    BufReader::new(cursor)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query::{Filtered, IgnoreCase};
+    use crate::{build::IndexBuilder, ioutil::Mem};
+
+    #[test]
+    fn test_header_checks() {
+        let mut builder = IndexBuilder::new();
+        builder.add_doc("doc0.txt", b"test string").unwrap();
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        assert!(output.ends_with(&MAGIC));
+
+        let error = |data: Vec<u8>| format!("{:#}", Index::new(Mem(data)).err().unwrap());
+        assert!(error(b"abc".to_vec()).contains("not a trident index"));
+        assert!(error(b"some other file format".to_vec()).contains("not a trident index"));
+        assert!(error(output[..output.len() - 1].to_vec()).contains("not a trident index"));
+        assert!(error(output[output.len() - 20..].to_vec()).contains("truncated"));
+
+        let mut future = output.clone();
+        let version_offset = future.len() - IndexHeader::TRAILER_BYTES;
+        let version = FORMAT_VERSION + 1;
+        future[version_offset..version_offset + 4].copy_from_slice(&version.to_le_bytes());
+        let expected = format!("unsupported index format version {}", version);
+        assert!(error(future).contains(&expected));
+
+        assert!(Index::new(Mem(output)).is_ok());
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut builder = IndexBuilder::new().store_content(true);
+        builder.add_doc("doc0.txt", b"test string 1").unwrap();
+        builder.add_doc("doc1.txt", b"abracadabra").unwrap();
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+
+        let index = Index::new(Mem(output.clone())).unwrap();
+        assert!(index.verify().unwrap().is_empty());
+
+        // Corrupt the first byte of the postings, which is the trigram of the first posting
+        let mut corrupt = output.clone();
+        corrupt[0] ^= 0xff;
+        let problems = Index::new(Mem(corrupt)).unwrap().verify().unwrap();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("trigram postings section has checksum"));
+        assert!(problems[1].contains("has a header for"));
+
+        let contents_offset = index.header.contents.offset as usize;
+        let mut corrupt = output;
+        corrupt[contents_offset + 5] ^= 0xff;
+        let problems = Index::new(Mem(corrupt)).unwrap().verify().unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("contents section has checksum"));
+    }
+
+    #[test]
+    fn test_corrupt_data() {
+        let mut builder = IndexBuilder::new().store_content(true);
+        for i in 0..8 {
+            let content = format!("fn item{}() {{ call({}) }}", i, i * 7);
+            builder
+                .add_doc_with_metadata(&format!("doc{}.rs", i), &[("n", "odd")], content.as_bytes())
+                .unwrap();
+        }
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+
+        // A posting whose header describes more data than the posting holds
+        let index = Index::new(Mem(output.clone())).unwrap();
+        let trigram = Trigram(*b"ite");
+        let posting = index
+            .header
+            .trigram_postings
+            .narrow(index.trigram_section(trigram).unwrap().unwrap())
+            .unwrap();
+        let docs_bytes_offset = posting.offset as usize + PostingHeader::SIZE_BYTES - 4;
+        let mut corrupt = output.clone();
+        corrupt[docs_bytes_offset..docs_bytes_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let index = Index::new(Mem(corrupt)).unwrap();
+        let err = index.search(&Query::parse("item1").unwrap()).unwrap_err();
+        assert!(matches!(err, TridentError::Corrupt(_)), "{}", err);
+        assert!(index.postings().any(|posting| posting.is_err()));
+
+        // Every read of an index with a corrupt byte anywhere in it either succeeds or returns an
+        // error, rather than panicking
+        let queries = [
+            "item1",
+            "fn",
+            "c",
+            "\"(7\"",
+            "\"call(21)\"",
+            "item AND NOT 3",
+        ]
+        .map(|q| Query::parse(q).unwrap());
+        for offset in 0..output.len() {
+            let mut corrupt = output.clone();
+            corrupt[offset] ^= 0xa5;
+            let Ok(index) = Index::new(Mem(corrupt)) else {
+                continue;
+            };
+            for query in queries.iter() {
+                let _ = index.search(query);
+            }
+            let _ = index.doc_name(5);
+            let _ = index.metadata();
+            let _ = index.filter_docs(&[Filter {
+                key: "n".to_string(),
+                value: "odd".to_string(),
+            }]);
+            let _ = index.verify();
+            index.postings().for_each(drop);
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let mut builder = IndexBuilder::new();
+        builder.add_doc("doc0.txt", b"test string 1").unwrap();
+        builder.add_doc("doc1.txt", b"test string 2").unwrap();
+        builder.add_doc("doc2.txt", b"abracadabra").unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+
+        let index = Index::new(Mem(output)).unwrap();
+        let doc_ids = index.candidates(b"string").collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0, 1]);
+
+        let doc_ids = index.candidates(b"strin").collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0, 1]);
+
+        let doc_ids = index.candidates(b"stri").collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0, 1]);
+
+        let doc_ids = index.candidates(b"str").collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0, 1]);
+
+        let doc_ids = index.candidates(b"abr").collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[2]);
+    }
+
+    #[test]
+    fn test_search_verified() {
+        let mut builder = IndexBuilder::new().store_content(true);
+        builder
+            .add_doc("doc0.txt", b"fn handle_request_timeout() {}")
+            .unwrap();
+        // Contains every (trigram, successor) pair of "handle_request_timeout", but not the
+        // whole string
+        builder
+            .add_doc("doc1.txt", b"handle_request_timeou andle_request_timeout")
+            .unwrap();
+        builder.add_doc("doc2.txt", b"").unwrap();
+        builder
+            .add_doc("doc3.txt", b"fn handle_timeout() { handle_request() }")
+            .unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        assert!(index.has_content());
+        assert_eq!(
+            index.content(3).unwrap(),
+            b"fn handle_timeout() { handle_request() }"
+        );
+        assert_eq!(index.content(2).unwrap(), b"");
+
+        let query = Query::Literal(b"handle_request_timeout".to_vec());
+        assert_eq!(
+            index
+                .candidates(b"handle_request_timeout")
+                .collect::<Vec<DocID>>(),
+            &[0, 1]
+        );
+        assert_eq!(index.search(&query).unwrap(), &[0]);
+
+        let query = Query::parse("handle NOT \"handle_request_timeout\"").unwrap();
+        assert_eq!(index.search(&query).unwrap(), &[1, 3]);
+
+        let query = RegexQuery::new("handle_\\w+\\(\\)").unwrap();
+        assert_eq!(index.search(&query).unwrap(), &[0, 3]);
+    }
+
+    #[test]
+    fn test_search_matches() {
+        let mut builder = IndexBuilder::new().store_content(true);
+        builder
+            .add_doc(
+                "doc0.txt",
+                b"use tokio::spawn;\n\nfn main() {\n    spawn(run());\n}\n",
+            )
+            .unwrap();
+        builder
+            .add_doc("doc1.txt", b"fn spawn_blocking() {}")
+            .unwrap();
+        builder.add_doc("doc2.txt", b"fn run() {}").unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        let query = Query::parse("spawn NOT blocking").unwrap();
+        let doc_matches = index.search_matches(&query, 1).unwrap();
+        assert_eq!(doc_matches.len(), 1);
+        assert_eq!(doc_matches[0].doc_id, 0);
+
+        let matches = &doc_matches[0].matches;
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].range, 11..16);
+        assert_eq!((matches[0].line_number, matches[0].column), (1, 12));
+        assert_eq!(matches[0].after, vec![b"".to_vec()]);
+        assert_eq!((matches[1].line_number, matches[1].column), (4, 5));
+        assert_eq!(matches[1].line, b"    spawn(run());");
+        assert_eq!(matches[1].before, vec![b"fn main() {".to_vec()]);
+        assert_eq!(matches[1].after, vec![b"}".to_vec()]);
+
+        let query = RegexQuery::new("fn \\w+\\(\\)").unwrap();
+        let doc_matches = index.search_matches(&query, 0).unwrap();
+        let locations = doc_matches
+            .iter()
+            .map(|d| (d.doc_id, d.matches[0].range.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(locations, vec![(0, 19..28), (1, 0..19), (2, 0..8)]);
+    }
+
+    #[test]
+    fn test_search_without_content() {
+        let mut builder = IndexBuilder::new();
+        builder.add_doc("doc0.txt", b"test string").unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        assert!(!index.has_content());
+        assert!(index.search(&Query::Literal(b"test".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_doc_names() {
+        let mut builder = IndexBuilder::new();
+        let names = (0..40)
+            .map(|i| format!("src/module_{}/file_{}.rs", i / 10, i))
+            .collect::<Vec<String>>();
+        for name in names.iter() {
+            builder.add_doc(name, b"content").unwrap();
+        }
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        assert_eq!(index.num_docs(), 40);
+        for (doc_id, name) in names.iter().enumerate() {
+            assert_eq!(&index.doc_name(doc_id as DocID).unwrap(), name);
+        }
+        assert!(index.doc_name(40).is_err());
+        let decoded = index.doc_names().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(decoded, names);
+        assert_eq!(index.find_docs("src/module_3/file_37.rs").unwrap(), [37]);
+    }
+
+    #[test]
+    fn test_search_filtered() {
+        let mut builder = IndexBuilder::new().store_content(true);
+        builder
+            .add_doc_with_metadata(
+                "core/src/lib.rs",
+                &[("repo", "core"), ("lang", "rust")],
+                b"fn spawn_task() {}",
+            )
+            .unwrap();
+        builder
+            .add_doc_with_metadata(
+                "core/scripts/spawn.py",
+                &[("repo", "core"), ("lang", "python")],
+                b"def spawn_task(): pass",
+            )
+            .unwrap();
+        builder
+            .add_doc_with_metadata(
+                "web/src/main.rs",
+                &[("repo", "web"), ("lang", "rust")],
+                b"fn spawn_task() {}",
+            )
+            .unwrap();
+        builder.add_doc("README.md", b"spawn_task").unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        let docs = |key: &str, value: &str| {
+            index
+                .metadata_docs(key, value)
+                .unwrap()
+                .iter()
+                .collect::<Vec<DocID>>()
+        };
+        assert_eq!(docs("lang", "rust"), &[0, 2]);
+        assert_eq!(docs("repo", "core"), &[0, 1]);
+        assert_eq!(docs("repo", "missing"), &[] as &[DocID]);
+
+        let query = Filtered::parse("lang:rust repo:core spawn_task").unwrap();
+        assert_eq!(query.candidates(&index).collect::<Vec<DocID>>(), &[0]);
+        assert_eq!(index.search(&query).unwrap(), &[0]);
+
+        let query = Filtered::parse("lang:rust").unwrap();
+        assert_eq!(index.search(&query).unwrap(), &[0, 2]);
+
+        let query = Filtered {
+            filters: Filtered::parse("repo:core").unwrap().filters,
+            query: Some(RegexQuery::new("def \\w+").unwrap()),
+        };
+        assert_eq!(index.search(&query).unwrap(), &[1]);
+    }
+
+    #[test]
+    fn test_search_case_insensitive() {
+        let mut builder = IndexBuilder::new().store_content(true);
+        builder
+            .add_doc("doc0.txt", b"let m = HashMap::new();")
+            .unwrap();
+        builder.add_doc("doc1.txt", b"a hashmap of names").unwrap();
+        builder
+            .add_doc("doc2.txt", b"HASHMAP_CAPACITY = 16")
+            .unwrap();
+        builder.add_doc("doc3.txt", b"let v = Vec::new();").unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        assert_eq!(
+            index
+                .candidates_with_case(b"hashmap", Case::Insensitive)
+                .collect::<Vec<DocID>>(),
+            &[0, 1, 2]
+        );
+        assert_eq!(
+            index
+                .candidates_with_case(b"hashmap", Case::Sensitive)
+                .collect::<Vec<DocID>>(),
+            &[1]
+        );
+
+        let query = Query::parse("hashmap NOT \"vec\"").unwrap();
+        assert_eq!(index.search(&query).unwrap(), &[1]);
+        assert_eq!(index.search(&IgnoreCase::new(&query)).unwrap(), &[0, 1, 2]);
+
+        let doc_matches = index
+            .search_matches(&IgnoreCase::new(&Query::parse("new").unwrap()), 0)
+            .unwrap();
+        let locations = doc_matches
+            .iter()
+            .map(|d| (d.doc_id, d.matches[0].range.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(locations, vec![(0, 17..20), (3, 13..16)]);
+
+        let query = RegexQuery::with_case("hashmap::\\w+", Case::Insensitive).unwrap();
+        assert_eq!(index.search(&query).unwrap(), &[0]);
+    }
+
+    #[test]
+    fn test_search_short_query() {
+        let mut builder = IndexBuilder::new();
+        builder.add_doc("doc0.txt", b"|a| a => b").unwrap();
+        builder.add_doc("doc1.txt", b"std::mem").unwrap();
+        builder.add_doc("doc2.txt", b"AB").unwrap();
+        builder.add_doc("doc3.txt", b"c").unwrap();
+        builder.add_doc("doc4.txt", b"").unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        let candidates =
+            |q: &[u8], case: Case| index.candidates_with_case(q, case).collect::<Vec<DocID>>();
+        assert_eq!(candidates(b"=>", Case::Sensitive), &[0]);
+        assert_eq!(candidates(b"::", Case::Sensitive), &[1]);
+        assert_eq!(candidates(b"b", Case::Sensitive), &[0]);
+        assert_eq!(candidates(b"b", Case::Insensitive), &[0, 2]);
+        assert_eq!(candidates(b"AB", Case::Sensitive), &[2]);
+        assert_eq!(candidates(b"c", Case::Sensitive), &[3]);
+        assert_eq!(candidates(b"zz", Case::Sensitive), &[] as &[DocID]);
+        assert_eq!(candidates(b"", Case::Sensitive), &[0, 1, 2, 3, 4]);
+
+        // Short literals are exact, so they can exclude candidates
+        let query = Query::parse("NOT \"::\" NOT =>").unwrap();
+        assert_eq!(
+            index.boolean_candidates(&query).collect::<Vec<DocID>>(),
+            &[2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn test_search_many_docs() {
+        let mut builder = IndexBuilder::new();
+        for i in 0..1000 {
+            let content = match i % 300 {
+                7 => format!("common doc {} with rare_value", i),
+                _ => format!("common doc {} with rare_other", i),
+            };
+            builder
+                .add_doc(&format!("doc{}.txt", i), content.as_bytes())
+                .unwrap();
+        }
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        // The doc lists span several blocks, which the mapping and intersection skip over
+        let doc_ids = index.candidates(b"rare_value").collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[7, 307, 607, 907]);
+
+        let query = Query::parse("common with rare_va").unwrap();
+        let doc_ids = index.boolean_candidates(&query).collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[7, 307, 607, 907]);
+
+        let mut candidates = index.candidates(b"common");
+        assert_eq!(candidates.advance_to(500), Some(500));
+        assert_eq!(candidates.advance_to(998), Some(998));
+        assert_eq!(candidates.next(), Some(999));
+        assert_eq!(candidates.next(), None);
+    }
+
+    #[test]
+    fn test_search_many_successors() {
+        let mut builder = IndexBuilder::new();
+        for i in 0..300 {
+            builder
+                .add_doc(&format!("doc{}.txt", i), format!("key{:03};", i).as_bytes())
+                .unwrap();
+        }
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        // The successors of "key" span several blocks
+        let candidates = |q: &[u8]| index.candidates(q).collect::<Vec<DocID>>();
+        assert_eq!(candidates(b"key123"), &[123]);
+        assert_eq!(candidates(b"key299"), &[299]);
+        assert_eq!(candidates(b"key300"), &[] as &[DocID]);
+        assert_eq!(candidates(b"key12"), (120..130).collect::<Vec<DocID>>());
+        assert_eq!(candidates(b"key2"), (200..300).collect::<Vec<DocID>>());
+        assert_eq!(candidates(b"key9"), &[] as &[DocID]);
+        assert_eq!(candidates(b"key/"), &[] as &[DocID]);
+    }
+
+    #[test]
+    fn test_search_successor_major() {
+        let docs: Vec<String> = (0..400)
+            .map(|i| format!("key{:03}; value{}", i % 150, i))
+            .collect();
+
+        let mut indexes = Vec::new();
+        for layout in [MatrixLayout::DocMajor, MatrixLayout::SuccessorMajor] {
+            let mut builder = IndexBuilder::new().matrix_layout(layout);
+            for (i, doc) in docs.iter().enumerate() {
+                builder
+                    .add_doc(&format!("doc{}.txt", i), doc.as_bytes())
+                    .unwrap();
+            }
+            let mut output = Vec::new();
+            let stats = builder.build(&mut output).unwrap();
+            assert!(stats.build.postings_sum.other_layout_successors_bytes > 0);
+            indexes.push(Index::new(Mem(output)).unwrap());
+        }
+        assert_eq!(
+            indexes[1].header.matrix_layout,
+            MatrixLayout::SuccessorMajor
+        );
+
+        for query in [
+            &b"key012"[..],
+            b"key01",
+            b"key1",
+            b"y; val",
+            b"value12",
+            b"key999",
+        ] {
+            let expected = (0..docs.len() as DocID)
+                .filter(|&i| {
+                    docs[i as usize]
+                        .as_bytes()
+                        .windows(query.len())
+                        .any(|w| w == query)
+                })
+                .collect::<Vec<DocID>>();
+            for index in indexes.iter() {
+                assert_eq!(index.candidates(query).collect::<Vec<DocID>>(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_spilled() {
+        let docs: Vec<String> = (0..50)
+            .map(|i| format!("key{:03}; value{}", i % 20, i))
+            .collect();
+
+        let build = |builder: IndexBuilder| {
+            let mut builder = builder;
+            for (i, doc) in docs.iter().enumerate() {
+                builder
+                    .add_doc(&format!("doc{}.txt", i), doc.as_bytes())
+                    .unwrap();
+            }
+            let mut output = Vec::new();
+            let stats = builder.build(&mut output).unwrap();
+            (output, stats)
+        };
+
+        let (expected, _) = build(IndexBuilder::new());
+        let (output, stats) = build(IndexBuilder::new().memory_budget(1000));
+        assert!(stats.extract.spilled_runs > 1);
+        assert_eq!(output, expected);
+
+        let index = Index::new(Mem(output)).unwrap();
+        assert_eq!(
+            index.candidates(b"key01").collect::<Vec<DocID>>(),
+            vec![10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39]
+        );
+    }
+
+    #[test]
+    fn test_search_long_query() {
+        let mut builder = IndexBuilder::new();
+        builder
+            .add_doc("doc0.txt", b"fn handle_request_timeout() {}")
+            .unwrap();
+        builder
+            .add_doc("doc1.txt", b"fn handle_response() {}")
+            .unwrap();
+        builder
+            .add_doc("doc2.txt", b"fn handle_request() {}")
+            .unwrap();
+        builder
+            .add_doc("doc3.txt", b"let timeout = handle_request_timeout;")
+            .unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+
+        let index = Index::new(Mem(output)).unwrap();
+        let doc_ids = index
+            .candidates(b"fn handle_request_timeout")
+            .collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0]);
+
+        let doc_ids = index
+            .candidates(b"handle_request_timeout")
+            .collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0, 3]);
+
+        let doc_ids = index.candidates(b"fn handle_re").collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0, 1, 2]);
+
+        let doc_ids = index.candidates(b"handle_nothing").collect::<Vec<DocID>>();
+        assert!(doc_ids.is_empty());
+    }
+
+    #[test]
+    fn test_search_boolean() {
+        let mut builder = IndexBuilder::new();
+        builder.add_doc("doc0.txt", b"tokio::spawn(task)").unwrap();
+        builder
+            .add_doc("doc1.txt", b"runtime.block_on(tokio_future)")
+            .unwrap();
+        builder
+            .add_doc("doc2.txt", b"#[tokio::test] fn spawn_test()")
+            .unwrap();
+        builder
+            .add_doc("doc3.txt", b"std::thread::spawn(task)")
+            .unwrap();
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        let candidates = |q: &str| {
+            index
+                .boolean_candidates(&Query::parse(q).unwrap())
+                .collect::<Vec<DocID>>()
+        };
+
+        assert_eq!(
+            candidates(r#""tokio" AND ("spawn" OR "block_on") NOT "test""#),
+            &[0, 1]
+        );
+        assert_eq!(candidates("spawn OR block_on"), &[0, 1, 2, 3]);
+        assert_eq!(candidates("tokio AND spawn"), &[0, 2]);
+        assert_eq!(candidates("NOT tokio"), &[3]);
+        assert_eq!(candidates("NOT (tokio OR thread)"), &[] as &[DocID]);
+
+        // A negated literal that is too long to be exact doesn't exclude anything
+        assert_eq!(candidates("spawn NOT block_on"), &[0, 2, 3]);
+        assert_eq!(candidates("spawn NOT tokio::test"), &[0, 2, 3]);
+    }
+
+    #[test]
+    fn test_search_regex() {
+        let docs: [&[u8]; 4] = [
+            b"tokio::spawn(async move {})",
+            b"runtime.block_on(future)",
+            b"std::thread::spawn(|| {})",
+            b"fn main() {}",
+        ];
+        let mut builder = IndexBuilder::new();
+        for (i, doc) in docs.iter().enumerate() {
+            builder.add_doc(&format!("doc{}.txt", i), doc).unwrap();
+        }
+
+        let mut output = Vec::new();
+        builder.build(&mut output).unwrap();
+        let index = Index::new(Mem(output)).unwrap();
+
+        let query = RegexQuery::new("(spawn|block_on)\\(").unwrap();
+        let doc_ids = index.regex_candidates(&query).collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0, 1, 2]);
+
+        let query = RegexQuery::new("tok.o::spawn").unwrap();
+        let doc_ids = index
+            .regex_candidates(&query)
+            .filter(|&doc_id| query.is_match(docs[doc_id as usize]))
+            .collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[0]);
+
+        let query = RegexQuery::new("fn \\w+\\(\\)").unwrap();
+        let doc_ids = index.regex_candidates(&query).collect::<Vec<DocID>>();
+        assert_eq!(&doc_ids, &[3]);
+    }
+}
@@ -0,0 +1,119 @@
+use std::ops::Range;
+
+// A single step of a query plan: a (trigram, successor) pair taken from the query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanStep {
+    // The range of the query covered by the pair. The first three bytes are the leading trigram,
+    // and the remaining (up to three) bytes are its successor.
+    pub range: Range<usize>,
+
+    // The estimated cost of searching the pair, relative to the other steps
+    pub cost: f32,
+}
+
+// A plan for searching a literal query: the set of (trigram, successor) pairs whose candidates
+// are intersected, ordered from cheapest to most expensive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl QueryPlan {
+    // Plans a query given the estimated cost of the posting for the trigram at every offset in
+    // the query, so `costs` has one entry for each of the `query_len - 2` trigrams.
+    //
+    // The plan always anchors on the rarest trigram in the query. The rest of the query is then
+    // covered by the set of pairs with the lowest total cost.
+    pub fn new(query_len: usize, costs: &[f32]) -> Self {
+        assert!(query_len >= 3);
+        assert!(costs.len() == query_len - 2);
+
+        let step_at = |offset: usize| PlanStep {
+            range: offset..query_len.min(offset + 6),
+            cost: costs[offset],
+        };
+
+        let anchor = (0..costs.len())
+            .min_by(|&a, &b| costs[a].total_cmp(&costs[b]))
+            .map(step_at)
+            .unwrap();
+
+        // best[p] is the cheapest set of offsets found so far that covers every byte before p
+        // that is not already covered by the anchor.
+        let mut best: Vec<Option<(f32, Vec<usize>)>> = vec![None; query_len + 1];
+        best[0] = Some((0., Vec::new()));
+        let mut cover = None;
+        for p in 0..=query_len {
+            let (cost, offsets) = match best[p].take() {
+                Some(b) => b,
+                None => continue,
+            };
+
+            let uncovered = match (p..query_len).find(|i| !anchor.range.contains(i)) {
+                Some(i) => i,
+                None => {
+                    if !matches!(&cover, Some((c, _)) if *c <= cost) {
+                        cover = Some((cost, offsets));
+                    }
+                    continue;
+                }
+            };
+
+            // Try every pair that covers the first uncovered byte.
+            for offset in uncovered.saturating_sub(5)..=uncovered.min(costs.len() - 1) {
+                let step = step_at(offset);
+                if step.range.end <= uncovered {
+                    continue;
+                }
+
+                let new_cost = cost + step.cost;
+                let end = step.range.end;
+                if !matches!(&best[end], Some((c, _)) if *c <= new_cost) {
+                    let mut new_offsets = offsets.clone();
+                    new_offsets.push(offset);
+                    best[end] = Some((new_cost, new_offsets));
+                }
+            }
+        }
+
+        let mut steps = vec![anchor];
+        steps.extend(cover.unwrap().1.into_iter().map(step_at));
+        steps.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+        Self { steps }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ranges(plan: &QueryPlan) -> Vec<Range<usize>> {
+        plan.steps.iter().map(|s| s.range.clone()).collect()
+    }
+
+    #[test]
+    fn test_short_query() {
+        let plan = QueryPlan::new(3, &[0.5]);
+        assert_eq!(ranges(&plan), vec![0..3]);
+
+        let plan = QueryPlan::new(6, &[0.5, 0.4, 0.3, 0.2]);
+        assert_eq!(ranges(&plan), vec![3..6, 0..6]);
+    }
+
+    #[test]
+    fn test_anchors_on_rarest() {
+        // A query that starts with common trigrams should anchor in the middle
+        let plan = QueryPlan::new(12, &[0.9, 0.9, 0.9, 0.9, 0.01, 0.5, 0.5, 0.5, 0.5, 0.5]);
+        assert_eq!(plan.steps[0].range, 4..10);
+        assert_eq!(ranges(&plan), vec![4..10, 6..12, 0..6]);
+    }
+
+    #[test]
+    fn test_cheapest_cover() {
+        // The tail of the query is covered by the two cheap pairs rather than the expensive pair
+        // that ends the query
+        let costs = [0.01, 0.9, 0.9, 0.9, 0.9, 0.9, 0.1, 0.9, 0.9, 0.1, 0.9];
+        let plan = QueryPlan::new(13, &costs);
+        assert_eq!(ranges(&plan), vec![0..6, 6..12, 9..13]);
+    }
+}
@@ -0,0 +1,672 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use anyhow::{anyhow, Context, Result};
+
+use super::layered::{read_tombstones, tombstones_path, write_tombstones, LayeredIndex};
+use super::{Index, MatrixLayout};
+use crate::bitmap::DocBitmap;
+use crate::build::merge::IndexMerger;
+use crate::build::IndexBuilder;
+use crate::ioutil::{write_atomically, Len, Mem, ReadAt};
+use crate::DocID;
+
+// The file in a segment directory that lists its segments, one file name per line
+const MANIFEST: &str = "MANIFEST";
+
+// Decides when the small segments of a segmented index are merged
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionPolicy {
+    // Segments with fewer live docs than this are small
+    pub small_segment_docs: u32,
+
+    // Small segments are merged in the background once there are this many of them
+    pub max_small_segments: usize,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self {
+            small_segment_docs: 100_000,
+            max_small_segments: 8,
+        }
+    }
+}
+
+// Where a segment is stored: a file for segments on disk, or a buffer for the in-memory segment.
+// Clones share the file or buffer, so every snapshot can hold its own copy of a segment's index.
+#[derive(Clone)]
+pub enum Storage {
+    File(Arc<File>),
+    Mem(Arc<Mem>),
+}
+
+impl ReadAt for Storage {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self {
+            Storage::File(f) => ReadAt::read_at(&**f, buf, offset),
+            Storage::Mem(m) => m.read_at(buf, offset),
+        }
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self {
+            Storage::File(f) => ReadAt::read_exact_at(&**f, buf, offset),
+            Storage::Mem(m) => m.read_exact_at(buf, offset),
+        }
+    }
+}
+
+impl Len for Storage {
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            Storage::File(f) => f.len(),
+            Storage::Mem(m) => m.len(),
+        }
+    }
+}
+
+// An index that is updated continuously, stored in a directory of immutable segments. New docs
+// are kept in an in-memory segment until they are flushed to a new segment on disk, and deleted
+// docs are recorded as tombstones next to the segment that holds them. Small segments are merged
+// on a background thread, which drops their deleted docs. Queries run against a `Snapshot`, which
+// is unaffected by later writes, flushes and compactions.
+//
+// Only one `SegmentedIndex` may have a directory open at a time. Dropping it waits for a running
+// compaction and discards its merged segment, along with any docs that have not been flushed.
+pub struct SegmentedIndex {
+    dir: PathBuf,
+    matrix_layout: MatrixLayout,
+    policy: CompactionPolicy,
+    max_memory_docs: usize,
+
+    // The segments on disk, in the order that their docs are numbered in a snapshot
+    segments: Vec<Segment>,
+    next_segment: u64,
+
+    // The docs that have not been flushed, and the index built from them for the latest snapshot,
+    // if it is still up to date
+    memory: Vec<MemoryDoc>,
+    memory_index: Option<Index<Storage>>,
+
+    compaction: Option<Compaction>,
+}
+
+struct Segment {
+    name: String,
+
+    // The segment's index along with its tombstones, which snapshots share until they change
+    index: Index<Storage>,
+
+    // Whether the tombstones have changed since they were written
+    dirty: bool,
+
+    // The docs of the segment by name that have not been deleted by name yet. It is built from
+    // the doc names the first time a doc is deleted, so later deletes don't decode every name.
+    names: Option<HashMap<String, Vec<DocID>>>,
+}
+
+impl Segment {
+    fn new(name: String, index: Index<Storage>) -> Self {
+        Self {
+            name,
+            index,
+            dirty: false,
+            names: None,
+        }
+    }
+
+    // Removes the docs with the given name from the name map and returns them
+    fn take_docs_named(&mut self, name: &str) -> Result<Vec<DocID>> {
+        if self.names.is_none() {
+            let mut names: HashMap<String, Vec<DocID>> = HashMap::new();
+            for (doc_id, doc_name) in (0..).zip(self.index.doc_names()) {
+                names.entry(doc_name?).or_default().push(doc_id);
+            }
+            self.names = Some(names);
+        }
+        Ok(self
+            .names
+            .as_mut()
+            .unwrap()
+            .remove(name)
+            .unwrap_or_default())
+    }
+
+    fn live_docs(&self) -> u32 {
+        let deleted = self.index.deleted().map_or(0, |d| d.count());
+        self.index.num_docs() - deleted as u32
+    }
+}
+
+struct MemoryDoc {
+    name: String,
+    metadata: Vec<(String, String)>,
+    content: Vec<u8>,
+}
+
+// A merge of segments running on a background thread
+struct Compaction {
+    // The name of each merged segment, with its index as of when the merge started. The docs that
+    // were deleted from it then are the ones the merge dropped.
+    inputs: Vec<(String, Index<Storage>)>,
+    name: String,
+
+    // Returns whether the merged segment was written, which it is not if every doc was deleted
+    handle: JoinHandle<Result<bool>>,
+}
+
+impl SegmentedIndex {
+    // Opens the segmented index in a directory, creating the directory if it does not exist.
+    // Segment files that are not in the manifest, left behind by an interrupted flush or
+    // compaction, are removed.
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("create index directory {:?}", dir))?;
+
+        let names = match fs::read_to_string(dir.join(MANIFEST)) {
+            Ok(manifest) => manifest.lines().map(str::to_string).collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err).context("read manifest"),
+        };
+
+        let mut segments = Vec::with_capacity(names.len());
+        let mut next_segment = 0;
+        for name in names {
+            let number = segment_number(&name)
+                .ok_or_else(|| anyhow!("invalid segment name {:?} in manifest", name))?;
+            next_segment = next_segment.max(number + 1);
+
+            let path = dir.join(&name);
+            let mut index = open_segment(&path)?;
+            read_tombstones(&mut index, &path)?;
+            segments.push(Segment::new(name, index));
+        }
+
+        let live: HashSet<&str> = segments.iter().map(|s| s.name.as_str()).collect();
+        for entry in fs::read_dir(dir)? {
+            let file_name = entry?.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            let segment = file_name.strip_suffix(".deleted").unwrap_or(file_name);
+            if segment_number(segment).is_some() && !live.contains(segment) {
+                fs::remove_file(dir.join(file_name))?;
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            matrix_layout: MatrixLayout::DocMajor,
+            policy: CompactionPolicy::default(),
+            max_memory_docs: 1000,
+            segments,
+            next_segment,
+            memory: Vec::new(),
+            memory_index: None,
+            compaction: None,
+        })
+    }
+
+    // Sets the matrix layout of new segments
+    pub fn matrix_layout(mut self, matrix_layout: MatrixLayout) -> Self {
+        self.matrix_layout = matrix_layout;
+        self
+    }
+
+    pub fn compaction_policy(mut self, policy: CompactionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Sets the number of docs that the in-memory segment can hold before it is flushed to disk
+    pub fn max_memory_docs(mut self, max_memory_docs: usize) -> Self {
+        self.max_memory_docs = max_memory_docs.max(1);
+        self
+    }
+
+    // Returns the number of segments on disk, not counting a segment being written by a
+    // compaction
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    // Adds a doc, which is visible to snapshots taken after this returns. The doc is only durable
+    // once it has been flushed, which happens when the in-memory segment is full.
+    pub fn add_doc(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        self.add_doc_with_metadata(name, &[], content)
+    }
+
+    pub fn add_doc_with_metadata(
+        &mut self,
+        name: &str,
+        metadata: &[(&str, &str)],
+        content: &[u8],
+    ) -> Result<()> {
+        self.memory.push(MemoryDoc {
+            name: name.to_string(),
+            metadata: metadata
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            content: content.to_vec(),
+        });
+        self.memory_index = None;
+        if self.memory.len() >= self.max_memory_docs {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Deletes every doc with the given name, so that a new version of a doc can be added in its
+    // place. This looks up the name in every segment. Returns the number of docs deleted.
+    pub fn delete(&mut self, name: &str) -> Result<usize> {
+        let mut deleted = 0;
+        for segment in self.segments.iter_mut() {
+            for doc_id in segment.take_docs_named(name)? {
+                if segment.index.is_deleted(doc_id) {
+                    continue;
+                }
+                segment.index.delete(doc_id)?;
+                segment.dirty = true;
+                deleted += 1;
+            }
+        }
+
+        let memory_docs = self.memory.len();
+        self.memory.retain(|doc| doc.name != name);
+        if self.memory.len() != memory_docs {
+            deleted += memory_docs - self.memory.len();
+            self.memory_index = None;
+        }
+        Ok(deleted)
+    }
+
+    // Returns a consistent view of every doc that has been added and not deleted
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        self.poll_compaction(false)?;
+
+        if self.memory_index.is_none() && !self.memory.is_empty() {
+            let mut output = Vec::new();
+            self.build_memory(&mut output)?;
+            self.memory_index = Some(Index::new(Storage::Mem(Arc::new(Mem(output))))?);
+        }
+
+        let segments = self.segments.iter().map(|s| s.index.clone());
+        let layered = LayeredIndex::from_layers(segments.chain(self.memory_index.clone()))
+            .context("too many docs across segments")?;
+        Ok(Snapshot(layered))
+    }
+
+    // Writes the in-memory segment and any new tombstones to disk, then starts a compaction if the
+    // policy calls for one
+    pub fn flush(&mut self) -> Result<()> {
+        self.poll_compaction(false)?;
+
+        if !self.memory.is_empty() {
+            let name = self.new_segment_name();
+            let path = self.dir.join(&name);
+            let mut w = BufWriter::new(File::create(&path)?);
+            self.build_memory(&mut w)?;
+            w.into_inner()?.sync_all()?;
+
+            self.segments.push(Segment::new(name, open_segment(&path)?));
+            self.memory.clear();
+            self.memory_index = None;
+        }
+
+        self.write_tombstones()?;
+        self.write_manifest()?;
+
+        if self.compaction.is_none() {
+            let small = self.small_segments();
+            if small.len() >= self.policy.max_small_segments.max(2) {
+                self.start_compaction(small);
+            }
+        }
+        Ok(())
+    }
+
+    // Flushes, then merges every small segment and waits for the merge to finish
+    pub fn compact(&mut self) -> Result<()> {
+        self.flush()?;
+        self.poll_compaction(true)?;
+        let small = self.small_segments();
+        if small.len() >= 2 {
+            self.start_compaction(small);
+            self.poll_compaction(true)?;
+        }
+        Ok(())
+    }
+
+    fn build_memory<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut builder = IndexBuilder::new()
+            .store_content(true)
+            .matrix_layout(self.matrix_layout);
+        for doc in self.memory.iter() {
+            let metadata: Vec<(&str, &str)> = doc
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            builder.add_doc_with_metadata(&doc.name, &metadata, &doc.content)?;
+        }
+        builder.build(w)?;
+        Ok(())
+    }
+
+    fn new_segment_name(&mut self) -> String {
+        let name = format!("segment-{:08}.idx", self.next_segment);
+        self.next_segment += 1;
+        name
+    }
+
+    // Returns the positions of the segments with few enough live docs to merge
+    fn small_segments(&self) -> Vec<usize> {
+        (0..self.segments.len())
+            .filter(|&i| self.segments[i].live_docs() < self.policy.small_segment_docs)
+            .collect()
+    }
+
+    fn start_compaction(&mut self, positions: Vec<usize>) {
+        let name = self.new_segment_name();
+        let inputs: Vec<(String, Index<Storage>)> = positions
+            .iter()
+            .map(|&i| {
+                let segment = &self.segments[i];
+                (segment.name.clone(), segment.index.clone())
+            })
+            .collect();
+        let indexes: Vec<Index<Storage>> = inputs.iter().map(|(_, index)| index.clone()).collect();
+        let path = self.dir.join(&name);
+        let matrix_layout = self.matrix_layout;
+
+        let handle = std::thread::spawn(move || -> Result<bool> {
+            let mut merger = IndexMerger::new(
+                IndexBuilder::new()
+                    .store_content(true)
+                    .matrix_layout(matrix_layout),
+            );
+            let mut live_docs = 0;
+            for index in indexes.iter() {
+                merger.add_index(index)?;
+                live_docs += index.num_docs() as usize - index.deleted().map_or(0, |d| d.count());
+            }
+            if live_docs == 0 {
+                return Ok(false);
+            }
+
+            let mut w = BufWriter::new(File::create(&path)?);
+            merger.build(&mut w)?;
+            w.into_inner()?.sync_all()?;
+            Ok(true)
+        });
+
+        self.compaction = Some(Compaction {
+            inputs,
+            name,
+            handle,
+        });
+    }
+
+    // Replaces the inputs of a finished compaction with the merged segment. If `wait` is set,
+    // waits for a running compaction to finish.
+    fn poll_compaction(&mut self, wait: bool) -> Result<()> {
+        match &self.compaction {
+            Some(compaction) if wait || compaction.handle.is_finished() => {}
+            _ => return Ok(()),
+        }
+        let compaction = self.compaction.take().unwrap();
+        let written = match compaction.handle.join() {
+            Ok(Ok(written)) => written,
+            result => {
+                // The merged segment may have been partly written. It isn't in the manifest, so
+                // the next open removes it if this fails.
+                let _ = fs::remove_file(self.dir.join(&compaction.name));
+                return match result {
+                    Err(_) => Err(anyhow!("compaction thread panicked")),
+                    Ok(result) => result.map(|_| ()).context("compact segments"),
+                };
+            }
+        };
+
+        // Docs that were deleted from the inputs while they were being merged must be deleted
+        // from the merged segment. The merge kept the live docs of each input in order.
+        let mut deleted_since = Vec::new();
+        let mut new_id: DocID = 0;
+        let mut position = self.segments.len();
+        for (name, before) in compaction.inputs.iter() {
+            let i = self.segments.iter().position(|s| &s.name == name).unwrap();
+            position = position.min(i);
+            let segment = &self.segments[i];
+            for doc_id in 0..segment.index.num_docs() {
+                if before.is_deleted(doc_id) {
+                    continue;
+                }
+                if segment.index.is_deleted(doc_id) {
+                    deleted_since.push(new_id);
+                }
+                new_id += 1;
+            }
+        }
+
+        let input_names: HashSet<&str> =
+            compaction.inputs.iter().map(|(n, _)| n.as_str()).collect();
+        let mut remaining = Vec::with_capacity(self.segments.len());
+        for (i, segment) in self.segments.drain(..).enumerate() {
+            if i == position && written {
+                let mut segment = Segment::new(
+                    compaction.name.clone(),
+                    open_segment(&self.dir.join(&compaction.name))?,
+                );
+                if !deleted_since.is_empty() {
+                    let deleted = DocBitmap::from_doc_ids(new_id, deleted_since.iter().copied());
+                    segment.index.set_deleted(deleted)?;
+                    segment.dirty = true;
+                }
+                remaining.push(segment);
+            }
+            if !input_names.contains(segment.name.as_str()) {
+                remaining.push(segment);
+            }
+        }
+        self.segments = remaining;
+
+        self.write_tombstones()?;
+        self.write_manifest()?;
+        for name in input_names {
+            let path = self.dir.join(name);
+            fs::remove_file(&path)?;
+            let tombstones = tombstones_path(&path);
+            if tombstones.exists() {
+                fs::remove_file(tombstones)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_tombstones(&mut self) -> Result<()> {
+        for segment in self.segments.iter_mut().filter(|s| s.dirty) {
+            write_tombstones(&segment.index, &self.dir.join(&segment.name))?;
+            segment.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        write_atomically(&self.dir.join(MANIFEST), |w| {
+            for segment in self.segments.iter() {
+                writeln!(w, "{}", segment.name)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Drop for SegmentedIndex {
+    // Waits for a running compaction, so that its thread doesn't write to the directory once
+    // another `SegmentedIndex` may have it open
+    fn drop(&mut self) {
+        if let Some(compaction) = self.compaction.take() {
+            let _ = compaction.handle.join();
+            let _ = fs::remove_file(self.dir.join(&compaction.name));
+        }
+    }
+}
+
+// Opens a segment on disk, without its tombstones
+fn open_segment(path: &Path) -> Result<Index<Storage>> {
+    let file = File::open(path)?;
+    Index::new(Storage::File(Arc::new(file))).with_context(|| format!("open segment {:?}", path))
+}
+
+// Returns the number of a segment from its file name
+fn segment_number(name: &str) -> Option<u64> {
+    name.strip_prefix("segment-")?
+        .strip_suffix(".idx")?
+        .parse()
+        .ok()
+}
+
+// A view of a segmented index at one point in time, searched as a layered index with a layer per
+// segment. The doc IDs of each segment follow those of the segments before it, so doc IDs are only
+// meaningful within the snapshot that returned them.
+#[derive(Clone)]
+pub struct Snapshot(LayeredIndex<Storage>);
+
+impl Deref for Snapshot {
+    type Target = LayeredIndex<Storage>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::query::Query;
+
+    fn names(snapshot: &Snapshot, query: &str) -> Vec<String> {
+        let query = Query::parse(query).unwrap();
+        let mut names: Vec<String> = snapshot
+            .search(&query)
+            .unwrap()
+            .into_iter()
+            .map(|doc_id| snapshot.doc_name(doc_id).unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_segmented_index() {
+        let dir = std::env::temp_dir().join(format!("trident-test-{:016x}", rand::random::<u64>()));
+        let policy = CompactionPolicy {
+            small_segment_docs: 10,
+            max_small_segments: 3,
+        };
+        let mut index = SegmentedIndex::open(&dir)
+            .unwrap()
+            .compaction_policy(policy)
+            .max_memory_docs(4);
+
+        for i in 0..10 {
+            index
+                .add_doc(
+                    &format!("doc{}.rs", i),
+                    format!("fn old{}() {{}}", i).as_bytes(),
+                )
+                .unwrap();
+        }
+        let before = index.snapshot().unwrap();
+        assert_eq!(names(&before, "old1"), ["doc1.rs"]);
+
+        // Replace a flushed doc and an in-memory doc, and delete another flushed doc
+        assert_eq!(index.delete("doc1.rs").unwrap(), 1);
+        index.add_doc("doc1.rs", b"fn new1() {}").unwrap();
+        assert_eq!(index.delete("doc9.rs").unwrap(), 1);
+        assert_eq!(index.delete("doc2.rs").unwrap(), 1);
+        assert_eq!(index.delete("doc2.rs").unwrap(), 0);
+
+        let after = index.snapshot().unwrap();
+        assert_eq!(
+            names(&after, "old"),
+            ["doc0.rs", "doc3.rs", "doc4.rs", "doc5.rs", "doc6.rs", "doc7.rs", "doc8.rs"]
+        );
+        assert_eq!(names(&after, "new"), ["doc1.rs"]);
+
+        // Earlier snapshots are unaffected by later writes
+        assert_eq!(names(&before, "old").len(), 10);
+        assert_eq!(names(&before, "new").len(), 0);
+
+        index.compact().unwrap();
+        assert_eq!(index.num_segments(), 1);
+        let compacted = index.snapshot().unwrap();
+        assert_eq!(compacted.num_docs(), 8);
+        assert_eq!(names(&compacted, "fn"), names(&after, "fn"));
+        assert_eq!(names(&before, "old").len(), 10);
+
+        // Deletes after the compaction are persisted as tombstones
+        index.delete("doc0.rs").unwrap();
+        index.flush().unwrap();
+        drop(index);
+
+        let mut reopened = SegmentedIndex::open(&dir).unwrap();
+        let snapshot = reopened.snapshot().unwrap();
+        assert_eq!(
+            names(&snapshot, "old"),
+            ["doc3.rs", "doc4.rs", "doc5.rs", "doc6.rs", "doc7.rs", "doc8.rs"]
+        );
+        assert_eq!(
+            snapshot
+                .content(snapshot.search(&Query::parse("new1").unwrap()).unwrap()[0])
+                .unwrap(),
+            b"fn new1() {}"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drop_during_compaction() {
+        let dir = std::env::temp_dir().join(format!("trident-test-{:016x}", rand::random::<u64>()));
+        let policy = CompactionPolicy {
+            small_segment_docs: 10,
+            max_small_segments: 2,
+        };
+        let mut index = SegmentedIndex::open(&dir)
+            .unwrap()
+            .compaction_policy(policy)
+            .max_memory_docs(1);
+
+        // The second flush starts a compaction, which is discarded when the index is dropped
+        index.add_doc("doc0.rs", b"fn zero() {}").unwrap();
+        index.add_doc("doc1.rs", b"fn one() {}").unwrap();
+        assert!(index.compaction.is_some());
+        drop(index);
+
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            ["MANIFEST", "segment-00000000.idx", "segment-00000001.idx"]
+        );
+
+        let mut reopened = SegmentedIndex::open(&dir).unwrap();
+        assert_eq!(reopened.num_segments(), 2);
+        assert_eq!(
+            names(&reopened.snapshot().unwrap(), "fn"),
+            ["doc0.rs", "doc1.rs"]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
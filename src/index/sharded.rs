@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use super::layered::LayeredIndex;
+use super::matches::DocMatch;
+use super::{DocIterator, Index};
+use crate::ioutil::{Len, ReadAt};
+use crate::query::SearchQuery;
+use crate::DocID;
+
+// An index that was split into shards as it was built, such as by `ShardedIndexBuilder`. The doc
+// IDs of each shard follow those of the shards before it. Searches run on every shard in parallel.
+pub struct ShardedIndex<R> {
+    shards: LayeredIndex<R>,
+}
+
+impl<R: ReadAt + Len> ShardedIndex<R> {
+    pub fn new(shards: Vec<Index<R>>) -> Result<Self> {
+        let mut shards = shards.into_iter();
+        let first = shards
+            .next()
+            .ok_or_else(|| anyhow!("a sharded index needs at least one shard"))?;
+        let mut layered = LayeredIndex::new(first);
+        for shard in shards {
+            layered.push_delta(shard)?;
+        }
+        Ok(Self { shards: layered })
+    }
+
+    pub fn shards(&self) -> &[Index<R>] {
+        self.shards.layers()
+    }
+
+    pub fn num_docs(&self) -> u32 {
+        self.shards.num_docs()
+    }
+
+    pub fn doc_name(&self, doc_id: DocID) -> Result<String> {
+        self.shards.doc_name(doc_id)
+    }
+
+    // Returns whether every shard stores the content of its docs
+    pub fn has_content(&self) -> bool {
+        self.shards.has_content()
+    }
+
+    pub fn content(&self, doc_id: DocID) -> Result<Vec<u8>> {
+        self.shards.content(doc_id)
+    }
+
+    pub fn candidates<'a, Q: SearchQuery + ?Sized>(
+        &'a self,
+        query: &Q,
+    ) -> Box<dyn DocIterator + 'a> {
+        self.shards.candidates(query)
+    }
+}
+
+impl<R: ReadAt + Len + Sync> ShardedIndex<R> {
+    // Returns the IDs of the docs that match a query, in order. Each shard is searched on its own
+    // thread.
+    pub fn search<Q: SearchQuery + Sync + ?Sized>(&self, query: &Q) -> Result<Vec<DocID>> {
+        self.map_shards(
+            |shard| Ok(shard.search(query)?),
+            |doc_id, offset| doc_id + offset,
+        )
+    }
+
+    // Returns the docs that match a query along with the location of each match in them, in order
+    // of doc ID. Each shard is searched on its own thread.
+    pub fn search_matches<Q: SearchQuery + Sync + ?Sized>(
+        &self,
+        query: &Q,
+        context: usize,
+    ) -> Result<Vec<DocMatch>> {
+        self.map_shards(
+            |shard| Ok(shard.search_matches(query, context)?),
+            |mut doc_match, offset| {
+                doc_match.doc_id += offset;
+                doc_match
+            },
+        )
+    }
+
+    // Runs `search` on every shard in parallel, then concatenates the results in shard order
+    // after applying `offset` to each of them with the first doc ID of its shard
+    fn map_shards<T, S, O>(&self, search: S, offset: O) -> Result<Vec<T>>
+    where
+        T: Send,
+        S: Fn(&Index<R>) -> Result<Vec<T>> + Sync,
+        O: Fn(T, DocID) -> T,
+    {
+        let results: Vec<Result<Vec<T>>> = std::thread::scope(|s| {
+            let handles: Vec<_> = self
+                .shards()
+                .iter()
+                .map(|shard| s.spawn(|| search(shard)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        let mut merged = Vec::new();
+        let mut first_doc_id: DocID = 0;
+        for (shard, result) in self.shards().iter().zip(results) {
+            merged.extend(result?.into_iter().map(|t| offset(t, first_doc_id)));
+            first_doc_id += shard.num_docs();
+        }
+        Ok(merged)
+    }
+}
+
+impl ShardedIndex<File> {
+    // Opens the shards listed in a manifest, along with the tombstones of any of them that have a
+    // sidecar file
+    pub fn open(manifest_path: &Path) -> Result<Self> {
+        let mut paths = read_manifest(manifest_path)?.into_iter();
+        let first = paths
+            .next()
+            .ok_or_else(|| anyhow!("shard manifest {:?} is empty", manifest_path))?;
+        Ok(Self {
+            shards: LayeredIndex::open(&first, &paths.collect::<Vec<_>>())?,
+        })
+    }
+}
+
+// Returns the path of each shard listed in a manifest. Shard paths are relative to the directory
+// of the manifest.
+pub fn read_manifest(manifest_path: &Path) -> Result<Vec<PathBuf>> {
+    let manifest = std::fs::read_to_string(manifest_path)
+        .with_context(|| format!("read shard manifest {:?}", manifest_path))?;
+    let dir = manifest_path.parent().unwrap_or(Path::new(""));
+    Ok(manifest.lines().map(|name| dir.join(name)).collect())
+}
+
+// Writes a manifest that lists the file names of the shards, one per line, in order
+pub fn write_manifest(manifest_path: &Path, shard_names: &[String]) -> Result<()> {
+    let mut w = BufWriter::new(File::create(manifest_path)?);
+    for name in shard_names {
+        writeln!(w, "{}", name)?;
+    }
+    w.flush()?;
+    Ok(())
+}
@@ -1,11 +1,13 @@
 use anyhow::Result;
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 
 use crate::build::serialize::StreamWriter;
+use crate::error::TridentError;
 
 pub trait ReadAt {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
@@ -37,12 +39,14 @@ pub struct Mem(pub Vec<u8>);
 
 impl ReadAt for Mem {
     fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let offset = offset.min(self.0.len() as u64);
         let sz = buf.len().min(self.0.len() - offset as usize);
         buf[..sz].copy_from_slice(&self.0[offset as usize..offset as usize + sz]);
         Ok(sz)
     }
 
     fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let offset = offset.min(self.0.len() as u64);
         let sz = buf.len().min(self.0.len() - offset as usize);
         if sz != buf.len() {
             return Err(io::Error::new(
@@ -65,17 +69,27 @@ impl Len for Mem {
 pub struct Cursor<T> {
     r: T,
     offset: u64,
+
+    // The offset that reads stop at
+    end: u64,
 }
 
 impl<T> Cursor<T> {
     pub fn new(r: T) -> Self {
-        Self { r, offset: 0 }
+        Self {
+            r,
+            offset: 0,
+            end: u64::MAX,
+        }
     }
 
+    // Returns a cursor that reads the bytes of a section, and reaches the end of its data at the
+    // end of the section, so that malformed data can't cause reads from the rest of the file
     pub fn new_in(r: T, section: Section) -> Self {
         Self {
             r,
             offset: section.offset,
+            end: section.offset.saturating_add(section.len),
         }
     }
 }
@@ -85,12 +99,22 @@ where
     T: ReadAt,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let n = self.r.read_at(buf, self.offset)?;
+        let remaining = self.end.saturating_sub(self.offset);
+        let len = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let n = self.r.read_at(&mut buf[..len], self.offset)?;
         self.offset += n as u64;
         Ok(n)
     }
 
     fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        if (buf.len() as u64) > self.end.saturating_sub(self.offset) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the section",
+            ));
+        }
         self.r.read_exact_at(buf, self.offset)?;
         self.offset += buf.len() as u64;
         Ok(())
@@ -100,7 +124,7 @@ where
 impl<T: Len> Seek for Cursor<&T> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
         match pos {
-            SeekFrom::Current(i) => self.offset = (self.offset as i64 + i) as u64,
+            SeekFrom::Current(i) => self.offset = self.offset.saturating_add_signed(i),
             SeekFrom::Start(i) => self.offset = i,
             SeekFrom::End(i) => self.offset = (self.r.len()? as i64 + i) as u64,
         };
@@ -123,7 +147,7 @@ impl<T: SectionType> StreamWriter for Section<T> {
     fn write_to<W: Write>(&self, w: &mut W) -> Result<usize> {
         w.write_u64::<LittleEndian>(self.offset)?;
         w.write_u64::<LittleEndian>(self.len)?;
-        Ok(std::mem::size_of::<u64> as usize * 2)
+        Ok(std::mem::size_of::<u64>() * 2)
     }
 }
 
@@ -136,8 +160,81 @@ impl<P: SectionType> Section<P> {
         }
     }
 
-    pub fn narrow(&self, child: Section<Self>) -> Self {
-        assert!(child.offset + child.len <= self.len);
-        Self::new(self.offset + child.offset, child.len)
+    // Returns the absolute location of a section within this one, or an error if it extends past
+    // the end of this one
+    pub fn narrow(&self, child: Section<Self>) -> crate::error::Result<Self> {
+        match child.offset.checked_add(child.len) {
+            Some(end) if end <= self.len => Ok(Self::new(
+                self.offset.saturating_add(child.offset),
+                child.len,
+            )),
+            _ => Err(TridentError::corrupt(format!(
+                "section at {}+{} is outside its parent of {} bytes",
+                child.offset, child.len, self.len
+            ))),
+        }
+    }
+}
+
+// A writer that computes the checksum of each section written through it
+pub struct ChecksumWriter<W> {
+    w: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            hasher: crc32fast::Hasher::new(),
+        }
     }
+
+    // Returns the checksum of everything written since the end of the previous section
+    pub fn finish_section(&mut self) -> u32 {
+        std::mem::take(&mut self.hasher).finalize()
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+// Returns the checksum of a section of a reader, as computed by `ChecksumWriter`
+pub fn section_checksum<R: ReadAt>(r: &R, section: Section) -> io::Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut offset = 0;
+    while offset < section.len {
+        let n = buf.len().min((section.len - offset) as usize);
+        r.read_exact_at(&mut buf[..n], section.offset + offset)?;
+        hasher.update(&buf[..n]);
+        offset += n as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+// Writes a file by writing a temporary file next to it and renaming it into place, so that
+// readers never see a partially written file
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    write(&mut w)?;
+    w.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
 }
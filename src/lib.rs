@@ -1,12 +1,14 @@
 #![feature(array_windows)]
 #![feature(is_sorted)]
-#![feature(split_array)]
 
 use std::fmt;
 
+pub mod bitmap;
 pub mod build;
+pub mod error;
 pub mod index;
 pub mod ioutil;
+pub mod query;
 
 pub type TrigramID = u32;
 pub type LocalSuccessorIdx = u32;
@@ -0,0 +1,359 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::index::{DocIterator, Failed, Index};
+use crate::ioutil::{Len, ReadAt};
+
+pub mod parse;
+pub mod regex;
+
+// A query that narrows down candidate docs using an index, then verifies each candidate against
+// its content.
+pub trait SearchQuery {
+    fn candidates<'a, R: ReadAt + Len>(&self, index: &'a Index<R>) -> Box<dyn DocIterator + 'a>;
+
+    // Returns whether the content actually matches the query
+    fn is_match(&self, content: &[u8]) -> bool;
+
+    // Returns the sorted byte ranges of the matches in content that matches the query
+    fn find_matches(&self, content: &[u8]) -> Vec<Range<usize>>;
+}
+
+// Whether a query must match the case of the content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Sensitive,
+    Insensitive,
+}
+
+// A boolean query over literal strings, as written by a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    // Matches documents that contain the literal
+    Literal(Vec<u8>),
+
+    // Matches documents that match every subquery
+    And(Vec<Query>),
+
+    // Matches documents that match any subquery
+    Or(Vec<Query>),
+
+    // Matches documents that do not match the subquery
+    Not(Box<Query>),
+}
+
+impl Query {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        parse::parse(s)
+    }
+
+    fn collapse(mut subqueries: Vec<Query>, f: fn(Vec<Query>) -> Self) -> Self {
+        match subqueries.len() {
+            1 => subqueries.pop().unwrap(),
+            _ => f(subqueries),
+        }
+    }
+}
+
+impl SearchQuery for Query {
+    fn candidates<'a, R: ReadAt + Len>(&self, index: &'a Index<R>) -> Box<dyn DocIterator + 'a> {
+        index.boolean_candidates(self)
+    }
+
+    fn is_match(&self, content: &[u8]) -> bool {
+        match self {
+            Query::Literal(literal) => memchr::memmem::find(content, literal).is_some(),
+            Query::And(subqueries) => subqueries.iter().all(|q| q.is_match(content)),
+            Query::Or(subqueries) => subqueries.iter().any(|q| q.is_match(content)),
+            Query::Not(q) => !q.is_match(content),
+        }
+    }
+
+    fn find_matches(&self, content: &[u8]) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        self.extend_matches(content, &mut ranges);
+        ranges.sort_by_key(|r| (r.start, r.end));
+        ranges.dedup();
+        ranges
+    }
+}
+
+impl Query {
+    fn to_ascii_lowercase(&self) -> Self {
+        match self {
+            Query::Literal(literal) => Query::Literal(literal.to_ascii_lowercase()),
+            Query::And(subqueries) => {
+                Query::And(subqueries.iter().map(Query::to_ascii_lowercase).collect())
+            }
+            Query::Or(subqueries) => {
+                Query::Or(subqueries.iter().map(Query::to_ascii_lowercase).collect())
+            }
+            Query::Not(q) => Query::Not(Box::new(q.to_ascii_lowercase())),
+        }
+    }
+
+    // Adds the occurrences of every literal that is not negated to `ranges`
+    fn extend_matches(&self, content: &[u8], ranges: &mut Vec<Range<usize>>) {
+        match self {
+            Query::Literal(literal) => ranges
+                .extend(memchr::memmem::find_iter(content, literal).map(|i| i..i + literal.len())),
+            Query::And(subqueries) | Query::Or(subqueries) => subqueries
+                .iter()
+                .for_each(|q| q.extend_matches(content, ranges)),
+            Query::Not(_) => {}
+        }
+    }
+}
+
+// A boolean query whose literals match regardless of ASCII case. Candidates are found by
+// searching every case variant of each (trigram, successor) pair, so the index can keep the
+// original content.
+#[derive(Debug, Clone)]
+pub struct IgnoreCase {
+    // The query with every literal lowercased, to verify against lowercased content
+    lowercase: Query,
+}
+
+impl IgnoreCase {
+    pub fn new(query: &Query) -> Self {
+        Self {
+            lowercase: query.to_ascii_lowercase(),
+        }
+    }
+}
+
+impl SearchQuery for IgnoreCase {
+    fn candidates<'a, R: ReadAt + Len>(&self, index: &'a Index<R>) -> Box<dyn DocIterator + 'a> {
+        index.boolean_candidates_with_case(&self.lowercase, Case::Insensitive)
+    }
+
+    fn is_match(&self, content: &[u8]) -> bool {
+        self.lowercase.is_match(&content.to_ascii_lowercase())
+    }
+
+    // ASCII lowercasing preserves byte offsets, so the ranges apply to the original content
+    fn find_matches(&self, content: &[u8]) -> Vec<Range<usize>> {
+        self.lowercase.find_matches(&content.to_ascii_lowercase())
+    }
+}
+
+// A metadata key and the value that a doc must have for it, written as `key:value`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub key: String,
+    pub value: String,
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.key, self.value)
+    }
+}
+
+// A query that only matches docs that pass every metadata filter. The filters select an exact set
+// of docs, which is intersected with the candidates of the query. Without a query, every doc that
+// passes the filters matches.
+#[derive(Debug, Clone)]
+pub struct Filtered<Q> {
+    pub filters: Vec<Filter>,
+    pub query: Option<Q>,
+}
+
+impl Filtered<Query> {
+    // Parses a boolean query that may contain `key:value` filters, which must be combined with
+    // the rest of the query by AND at the top level.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        parse::parse_filtered(s)
+    }
+}
+
+impl<Q: SearchQuery> SearchQuery for Filtered<Q> {
+    fn candidates<'a, R: ReadAt + Len>(&self, index: &'a Index<R>) -> Box<dyn DocIterator + 'a> {
+        let candidates = self.query.as_ref().map(|q| q.candidates(index));
+        match index.filtered_candidates(candidates, &self.filters) {
+            Ok(docs) => docs,
+            Err(err) => Box::new(Failed::new(err)),
+        }
+    }
+
+    fn is_match(&self, content: &[u8]) -> bool {
+        match &self.query {
+            Some(q) => q.is_match(content),
+            None => true,
+        }
+    }
+
+    fn find_matches(&self, content: &[u8]) -> Vec<Range<usize>> {
+        match &self.query {
+            Some(q) => q.find_matches(content),
+            None => Vec::new(),
+        }
+    }
+}
+
+// A boolean query over literal strings that a matching document must contain. Each literal is
+// searched with `Index::candidates`, so literals of six bytes or longer use the successor matrix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrigramQuery {
+    // Matches every document
+    All,
+
+    // Matches no documents
+    None,
+
+    // Matches documents that contain the literal, which is at least three bytes long
+    Literal(Vec<u8>),
+
+    // Matches documents that match every subquery
+    And(Vec<TrigramQuery>),
+
+    // Matches documents that match any subquery
+    Or(Vec<TrigramQuery>),
+}
+
+impl TrigramQuery {
+    // Returns a query matching any of the given strings. If any of the strings is too short to
+    // contain a trigram, every document may match.
+    pub fn any_of<'a>(strings: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        strings
+            .into_iter()
+            .fold(TrigramQuery::None, |q, s| match s.len() {
+                0..=2 => TrigramQuery::All,
+                _ => q.or(TrigramQuery::Literal(s.to_vec())),
+            })
+    }
+
+    pub fn and(self, other: TrigramQuery) -> Self {
+        use TrigramQuery::*;
+        let subqueries = match (self, other) {
+            (None, _) | (_, None) => return None,
+            (All, q) | (q, All) => return q,
+            (And(mut a), And(b)) => {
+                b.into_iter().for_each(|q| push_and(&mut a, q));
+                a
+            }
+            (And(mut a), q) | (q, And(mut a)) => {
+                push_and(&mut a, q);
+                a
+            }
+            (a, b) => {
+                let mut subqueries = vec![a];
+                push_and(&mut subqueries, b);
+                subqueries
+            }
+        };
+        Self::collapse(subqueries, And)
+    }
+
+    pub fn or(self, other: TrigramQuery) -> Self {
+        use TrigramQuery::*;
+        let subqueries = match (self, other) {
+            (All, _) | (_, All) => return All,
+            (None, q) | (q, None) => return q,
+            (Or(mut a), Or(b)) => {
+                b.into_iter().for_each(|q| push_or(&mut a, q));
+                a
+            }
+            (Or(mut a), q) | (q, Or(mut a)) => {
+                push_or(&mut a, q);
+                a
+            }
+            (a, b) => {
+                let mut subqueries = vec![a];
+                push_or(&mut subqueries, b);
+                subqueries
+            }
+        };
+        Self::collapse(subqueries, Or)
+    }
+
+    fn collapse(mut subqueries: Vec<TrigramQuery>, f: fn(Vec<TrigramQuery>) -> Self) -> Self {
+        match subqueries.len() {
+            1 => subqueries.pop().unwrap(),
+            _ => f(subqueries),
+        }
+    }
+}
+
+// Adds a subquery to a conjunction unless it is implied by an existing subquery. A literal
+// implies every literal it contains.
+fn push_and(subqueries: &mut Vec<TrigramQuery>, q: TrigramQuery) {
+    if let TrigramQuery::Literal(l) = &q {
+        if subqueries
+            .iter()
+            .any(|s| matches!(s, TrigramQuery::Literal(m) if contains(m, l)))
+        {
+            return;
+        }
+        subqueries.retain(|s| !matches!(s, TrigramQuery::Literal(m) if contains(l, m)));
+    } else if subqueries.contains(&q) {
+        return;
+    }
+    subqueries.push(q);
+}
+
+// Adds a subquery to a disjunction unless it implies an existing subquery.
+fn push_or(subqueries: &mut Vec<TrigramQuery>, q: TrigramQuery) {
+    if let TrigramQuery::Literal(l) = &q {
+        if subqueries
+            .iter()
+            .any(|s| matches!(s, TrigramQuery::Literal(m) if contains(l, m)))
+        {
+            return;
+        }
+        subqueries.retain(|s| !matches!(s, TrigramQuery::Literal(m) if contains(m, l)));
+    } else if subqueries.contains(&q) {
+        return;
+    }
+    subqueries.push(q);
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use TrigramQuery::*;
+
+    fn lit(s: &str) -> TrigramQuery {
+        Literal(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_simplify() {
+        assert_eq!(All.and(lit("abc")), lit("abc"));
+        assert_eq!(None.and(lit("abc")), None);
+        assert_eq!(All.or(lit("abc")), All);
+        assert_eq!(None.or(lit("abc")), lit("abc"));
+        assert_eq!(lit("abc").and(lit("abc")), lit("abc"));
+        assert_eq!(
+            lit("abc").and(lit("def")).and(lit("ghi")),
+            And(vec![lit("abc"), lit("def"), lit("ghi")])
+        );
+        assert_eq!(
+            lit("abc").or(lit("def").or(lit("abc"))),
+            Or(vec![lit("def"), lit("abc")])
+        );
+    }
+
+    #[test]
+    fn test_simplify_contained_literals() {
+        assert_eq!(lit("abc").and(lit("xabcx")), lit("xabcx"));
+        assert_eq!(lit("xabcx").and(lit("abc")), lit("xabcx"));
+        assert_eq!(lit("abc").or(lit("xabcx")), lit("abc"));
+        assert_eq!(lit("abc").and(lit("def")).and(lit("abcdef")), lit("abcdef"));
+    }
+
+    #[test]
+    fn test_any_of() {
+        assert_eq!(TrigramQuery::any_of([]), None);
+        assert_eq!(TrigramQuery::any_of([&b"abc"[..]]), lit("abc"));
+        assert_eq!(TrigramQuery::any_of([&b"abc"[..], b"ab"]), All);
+        assert_eq!(
+            TrigramQuery::any_of([&b"abc"[..], b"defg"]),
+            Or(vec![lit("abc"), lit("defg")])
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use anyhow::{anyhow, Result};
+
+use super::{Filter, Filtered, Query};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(Vec<u8>),
+    Filter(Filter),
+    And,
+    Or,
+    Not,
+    LeftParen,
+    RightParen,
+}
+
+// Parses a boolean query. The grammar, from lowest to highest precedence, is:
+//
+//   or      := and ("OR" and)*
+//   and     := unary ("AND"? unary)*
+//   unary   := "NOT" unary | primary
+//   primary := literal | "(" or ")"
+//
+// A literal is either a double-quoted string, in which `\"` and `\\` are escapes, or a bare word
+// that is not one of the keywords. Juxtaposed subqueries are implicitly combined with AND, so
+// `"a" NOT "b"` is the same as `"a" AND NOT "b"`.
+pub fn parse(s: &str) -> Result<Query> {
+    parse_tokens(tokenize(s.as_bytes(), false)?)
+}
+
+// Parses a boolean query in which bare words of the form `key:value` are metadata filters rather
+// than literals. Filters can only be combined with the rest of the query by AND at the top level,
+// since they are applied to the candidates of the whole query. A quoted string is never a filter.
+pub fn parse_filtered(s: &str) -> Result<Filtered<Query>> {
+    let (filters, tokens) = extract_filters(tokenize(s.as_bytes(), true)?)?;
+    let query = match tokens.is_empty() && !filters.is_empty() {
+        true => None,
+        false => Some(parse_tokens(tokens)?),
+    };
+    Ok(Filtered { filters, query })
+}
+
+fn parse_tokens(tokens: Vec<Token>) -> Result<Query> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let query = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(query),
+        Some(t) => Err(anyhow!(
+            "unexpected token {:?} at position {}",
+            t,
+            parser.pos
+        )),
+    }
+}
+
+// Removes the filters from the top level of a query, along with the AND that joins each one to
+// the rest of the query.
+fn extract_filters(tokens: Vec<Token>) -> Result<(Vec<Filter>, Vec<Token>)> {
+    let mut filters = Vec::new();
+    let mut rest = Vec::new();
+    let mut depth = 0;
+    let mut has_or = false;
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::LeftParen => depth += 1,
+            Token::RightParen => depth -= 1,
+            Token::Or if depth == 0 => has_or = true,
+            Token::Filter(filter) => {
+                if depth != 0 || rest.last() == Some(&Token::Not) {
+                    return Err(anyhow!(
+                        "filter {} must be combined with the query by AND at the top level",
+                        filter
+                    ));
+                }
+                if tokens.peek() == Some(&Token::And) {
+                    tokens.next();
+                } else if rest.last() == Some(&Token::And) {
+                    rest.pop();
+                }
+                filters.push(filter);
+                continue;
+            }
+            _ => {}
+        }
+        rest.push(token);
+    }
+
+    if has_or && !filters.is_empty() {
+        return Err(anyhow!(
+            "filters cannot be combined with a top-level OR, which must be grouped in parentheses"
+        ));
+    }
+    Ok((filters, rest))
+}
+
+// Returns the filter that a bare word represents, if any. The key must be a nonempty word and the
+// value must not start with another ':', so paths like `tokio::spawn` stay literals.
+fn parse_filter(word: &[u8]) -> Option<Filter> {
+    let colon = word.iter().position(|&b| b == b':')?;
+    let (key, value) = (&word[..colon], &word[colon + 1..]);
+    let is_key = !key.is_empty()
+        && key
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || b"_-".contains(b));
+    if !is_key || value.is_empty() || value[0] == b':' {
+        return None;
+    }
+    Some(Filter {
+        key: String::from_utf8_lossy(key).into_owned(),
+        value: String::from_utf8_lossy(value).into_owned(),
+    })
+}
+
+fn tokenize(s: &[u8], filters: bool) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b if b.is_ascii_whitespace() => i += 1,
+            b'(' => {
+                tokens.push(Token::LeftParen);
+                i += 1;
+            }
+            b')' => {
+                tokens.push(Token::RightParen);
+                i += 1;
+            }
+            b'"' => {
+                let start = i;
+                let mut literal = Vec::new();
+                i += 1;
+                loop {
+                    match s.get(i..) {
+                        Some([b'"', ..]) => break,
+                        Some([b'\\', b @ (b'"' | b'\\'), ..]) => {
+                            literal.push(*b);
+                            i += 1;
+                        }
+                        Some([b, ..]) => literal.push(*b),
+                        _ => return Err(anyhow!("unterminated string at offset {}", start)),
+                    }
+                    i += 1;
+                }
+                tokens.push(Token::Literal(literal));
+                i += 1;
+            }
+            _ => {
+                let start = i;
+                while i < s.len() && !s[i].is_ascii_whitespace() && !b"()\"".contains(&s[i]) {
+                    i += 1;
+                }
+                tokens.push(match &s[start..i] {
+                    b"AND" => Token::And,
+                    b"OR" => Token::Or,
+                    b"NOT" => Token::Not,
+                    word => match filters.then(|| parse_filter(word)).flatten() {
+                        Some(filter) => Token::Filter(filter),
+                        None => Token::Literal(word.to_vec()),
+                    },
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Query> {
+        let mut subqueries = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            subqueries.push(self.parse_and()?);
+        }
+        Ok(Query::collapse(subqueries, Query::Or))
+    }
+
+    fn parse_and(&mut self) -> Result<Query> {
+        let mut subqueries = vec![self.parse_unary()?];
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                }
+                Some(Token::Not | Token::Literal(_) | Token::LeftParen) => {}
+                _ => break,
+            }
+            subqueries.push(self.parse_unary()?);
+        }
+        Ok(Query::collapse(subqueries, Query::And))
+    }
+
+    fn parse_unary(&mut self) -> Result<Query> {
+        match self.next() {
+            Some(Token::Not) => Ok(Query::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Literal(literal)) => Ok(Query::Literal(literal)),
+            Some(Token::LeftParen) => {
+                let query = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RightParen) => Ok(query),
+                    _ => Err(anyhow!("expected ')' at position {}", self.pos - 1)),
+                }
+            }
+            Some(t) => Err(anyhow!(
+                "unexpected token {:?} at position {}",
+                t,
+                self.pos - 1
+            )),
+            None => Err(anyhow!("unexpected end of query")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Query::*;
+
+    fn lit(s: &str) -> Query {
+        Literal(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("tokio").unwrap(), lit("tokio"));
+        assert_eq!(parse("\"fn main\"").unwrap(), lit("fn main"));
+        assert_eq!(parse(r#""say \"hi\" \\o""#).unwrap(), lit(r#"say "hi" \o"#));
+        assert_eq!(
+            parse("a AND b OR c").unwrap(),
+            Or(vec![And(vec![lit("a"), lit("b")]), lit("c")])
+        );
+        assert_eq!(
+            parse("a AND (b OR c)").unwrap(),
+            And(vec![lit("a"), Or(vec![lit("b"), lit("c")])])
+        );
+        assert_eq!(parse("NOT a").unwrap(), Not(Box::new(lit("a"))));
+    }
+
+    #[test]
+    fn test_parse_implicit_and() {
+        assert_eq!(
+            parse(r#""tokio" AND ("spawn" OR "block_on") NOT "test""#).unwrap(),
+            And(vec![
+                lit("tokio"),
+                Or(vec![lit("spawn"), lit("block_on")]),
+                Not(Box::new(lit("test"))),
+            ])
+        );
+        assert_eq!(parse("a b").unwrap(), And(vec![lit("a"), lit("b")]));
+    }
+
+    #[test]
+    fn test_parse_filtered() {
+        let filter = |key: &str, value: &str| Filter {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+
+        let filtered = parse_filtered("lang:rust repo:core tokio::spawn").unwrap();
+        assert_eq!(
+            filtered.filters,
+            vec![filter("lang", "rust"), filter("repo", "core")]
+        );
+        assert_eq!(filtered.query, Some(lit("tokio::spawn")));
+
+        let filtered = parse_filtered("a AND lang:rust AND (b OR c)").unwrap();
+        assert_eq!(filtered.filters, vec![filter("lang", "rust")]);
+        assert_eq!(
+            filtered.query,
+            Some(And(vec![lit("a"), Or(vec![lit("b"), lit("c")])]))
+        );
+
+        let filtered = parse_filtered("owner:me").unwrap();
+        assert_eq!(filtered.filters, vec![filter("owner", "me")]);
+        assert_eq!(filtered.query, None);
+
+        let filtered = parse_filtered(r#""lang:rust""#).unwrap();
+        assert!(filtered.filters.is_empty());
+        assert_eq!(filtered.query, Some(lit("lang:rust")));
+
+        assert_eq!(parse("lang:rust").unwrap(), lit("lang:rust"));
+        assert!(parse_filtered("NOT lang:rust").is_err());
+        assert!(parse_filtered("a OR lang:rust").is_err());
+        assert!(parse_filtered("lang:rust a OR b").is_err());
+        assert!(parse_filtered("(a lang:rust)").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("\"abc").is_err());
+        assert!(parse("(a OR b").is_err());
+        assert!(parse("a OR").is_err());
+        assert!(parse("a)").is_err());
+        assert!(parse("AND a").is_err());
+    }
+}
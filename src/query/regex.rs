@@ -0,0 +1,395 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use anyhow::Result;
+use regex_syntax::hir::{Class, Hir, HirKind, Literal, RepetitionKind, RepetitionRange};
+use regex_syntax::ParserBuilder;
+
+use super::{Case, SearchQuery, TrigramQuery};
+use crate::index::{DocIterator, Index};
+use crate::ioutil::{Len, ReadAt};
+
+// The maximum number of strings in an exact, prefix or suffix set before it is simplified
+const MAX_SET_SIZE: usize = 16;
+
+// The maximum number of characters in a class before it is treated as matching any character
+const MAX_CLASS_SIZE: usize = 8;
+
+// A regex search, compiled into the trigram query that a matching document must satisfy. The
+// trigram query only narrows down the candidates, so every candidate must still be verified with
+// `SearchQuery::is_match` against its content.
+#[derive(Debug, Clone)]
+pub struct RegexQuery {
+    regex: regex::bytes::Regex,
+    trigram_query: TrigramQuery,
+}
+
+impl RegexQuery {
+    pub fn new(pattern: &str) -> Result<Self> {
+        Self::with_case(pattern, Case::Sensitive)
+    }
+
+    // Compiles a regex that, if the search is case-insensitive, matches regardless of case as if
+    // it started with `(?i)`.
+    pub fn with_case(pattern: &str, case: Case) -> Result<Self> {
+        let case_insensitive = case == Case::Insensitive;
+        let hir = ParserBuilder::new()
+            .allow_invalid_utf8(true)
+            .case_insensitive(case_insensitive)
+            .build()
+            .parse(pattern)?;
+        let trigram_query = RegexInfo::analyze(&hir).into_query();
+        let regex = regex::bytes::RegexBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .build()?;
+        Ok(Self {
+            regex,
+            trigram_query,
+        })
+    }
+
+    pub fn trigram_query(&self) -> &TrigramQuery {
+        &self.trigram_query
+    }
+
+    pub fn regex(&self) -> &regex::bytes::Regex {
+        &self.regex
+    }
+}
+
+impl SearchQuery for RegexQuery {
+    fn candidates<'a, R: ReadAt + Len>(&self, index: &'a Index<R>) -> Box<dyn DocIterator + 'a> {
+        index.regex_candidates(self)
+    }
+
+    fn is_match(&self, content: &[u8]) -> bool {
+        self.regex.is_match(content)
+    }
+
+    fn find_matches(&self, content: &[u8]) -> Vec<Range<usize>> {
+        self.regex.find_iter(content).map(|m| m.range()).collect()
+    }
+}
+
+type StringSet = BTreeSet<Vec<u8>>;
+
+// What is known about the strings matched by a regex, following the approach used by Google
+// Code Search (https://swtch.com/~rsc/regexp/regexp4.html).
+#[derive(Debug, Clone)]
+struct RegexInfo {
+    // The exact set of strings matched by the regex, if it is known and small
+    exact: Option<StringSet>,
+
+    // A set of strings such that every match begins with one of them. Ignored if `exact` is set.
+    prefix: StringSet,
+
+    // A set of strings such that every match ends with one of them. Ignored if `exact` is set.
+    suffix: StringSet,
+
+    // A query that every document containing a match satisfies, in addition to the sets above
+    query: TrigramQuery,
+}
+
+impl RegexInfo {
+    fn analyze(hir: &Hir) -> Self {
+        match hir.kind() {
+            HirKind::Empty | HirKind::Anchor(_) | HirKind::WordBoundary(_) => {
+                Self::exact([Vec::new()].into())
+            }
+            HirKind::Literal(Literal::Unicode(c)) => {
+                Self::exact([c.to_string().into_bytes()].into())
+            }
+            HirKind::Literal(Literal::Byte(b)) => Self::exact([vec![*b]].into()),
+            HirKind::Class(Class::Unicode(class)) => {
+                let mut chars = class
+                    .iter()
+                    .flat_map(|r| (r.start() as u32..=r.end() as u32).filter_map(char::from_u32));
+                let set: StringSet = chars
+                    .by_ref()
+                    .take(MAX_CLASS_SIZE + 1)
+                    .map(|c| c.to_string().into_bytes())
+                    .collect();
+                match set.len() {
+                    0..=MAX_CLASS_SIZE => Self::exact(set),
+                    _ => Self::any(),
+                }
+            }
+            HirKind::Class(Class::Bytes(class)) => {
+                let set: StringSet = class
+                    .iter()
+                    .flat_map(|r| r.start()..=r.end())
+                    .take(MAX_CLASS_SIZE + 1)
+                    .map(|b| vec![b])
+                    .collect();
+                match set.len() {
+                    0..=MAX_CLASS_SIZE => Self::exact(set),
+                    _ => Self::any(),
+                }
+            }
+            HirKind::Group(group) => Self::analyze(&group.hir),
+            HirKind::Repetition(rep) => {
+                let sub = Self::analyze(&rep.hir);
+                match rep.kind {
+                    RepetitionKind::ZeroOrOne => sub.optional(),
+                    RepetitionKind::Range(RepetitionRange::Exactly(1))
+                    | RepetitionKind::Range(RepetitionRange::Bounded(1, 1)) => sub,
+                    RepetitionKind::ZeroOrMore
+                    | RepetitionKind::Range(RepetitionRange::Exactly(0))
+                    | RepetitionKind::Range(RepetitionRange::AtLeast(0))
+                    | RepetitionKind::Range(RepetitionRange::Bounded(0, _)) => Self::any(),
+                    RepetitionKind::OneOrMore | RepetitionKind::Range(_) => sub.at_least_once(),
+                }
+            }
+            HirKind::Concat(hirs) => hirs
+                .iter()
+                .map(Self::analyze)
+                .reduce(Self::concat)
+                .unwrap_or_else(|| Self::exact([Vec::new()].into())),
+            HirKind::Alternation(hirs) => hirs
+                .iter()
+                .map(Self::analyze)
+                .reduce(Self::alternate)
+                .unwrap_or_else(|| Self::exact([Vec::new()].into())),
+        }
+    }
+
+    fn exact(set: StringSet) -> Self {
+        Self {
+            exact: Some(set),
+            prefix: StringSet::new(),
+            suffix: StringSet::new(),
+            query: TrigramQuery::All,
+        }
+    }
+
+    // Info for a regex that we know nothing about
+    fn any() -> Self {
+        Self {
+            exact: None,
+            prefix: [Vec::new()].into(),
+            suffix: [Vec::new()].into(),
+            query: TrigramQuery::All,
+        }
+    }
+
+    fn prefix(&self) -> &StringSet {
+        self.exact.as_ref().unwrap_or(&self.prefix)
+    }
+
+    fn suffix(&self) -> &StringSet {
+        self.exact.as_ref().unwrap_or(&self.suffix)
+    }
+
+    // Converts the exact set, if any, into a prefix set, suffix set and query
+    fn inexact(mut self) -> Self {
+        if let Some(exact) = self.exact.take() {
+            self.query = self
+                .query
+                .and(TrigramQuery::any_of(exact.iter().map(Vec::as_slice)));
+            self.prefix = exact.clone();
+            self.suffix = exact;
+            self.simplify();
+        }
+        self
+    }
+
+    // Shrinks the prefix and suffix sets until they are small enough, adding what is known about
+    // them to the query before any information is lost.
+    fn simplify(&mut self) {
+        if self.prefix.len() > MAX_SET_SIZE {
+            self.query = std::mem::replace(&mut self.query, TrigramQuery::All)
+                .and(TrigramQuery::any_of(self.prefix.iter().map(Vec::as_slice)));
+            for len in (0..=2).rev() {
+                self.prefix = self
+                    .prefix
+                    .iter()
+                    .map(|s| s[..s.len().min(len)].to_vec())
+                    .collect();
+                if self.prefix.len() <= MAX_SET_SIZE {
+                    break;
+                }
+            }
+        }
+
+        if self.suffix.len() > MAX_SET_SIZE {
+            self.query = std::mem::replace(&mut self.query, TrigramQuery::All)
+                .and(TrigramQuery::any_of(self.suffix.iter().map(Vec::as_slice)));
+            for len in (0..=2).rev() {
+                self.suffix = self
+                    .suffix
+                    .iter()
+                    .map(|s| s[s.len().saturating_sub(len)..].to_vec())
+                    .collect();
+                if self.suffix.len() <= MAX_SET_SIZE {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn optional(self) -> Self {
+        match self.exact {
+            Some(mut exact) => {
+                exact.insert(Vec::new());
+                Self::exact(exact)
+            }
+            None => Self::any(),
+        }
+    }
+
+    fn at_least_once(self) -> Self {
+        self.inexact()
+    }
+
+    fn concat(self, other: Self) -> Self {
+        if let (Some(a), Some(b)) = (&self.exact, &other.exact) {
+            if a.len() * b.len() <= MAX_SET_SIZE {
+                let mut res = Self::exact(cross(a, b));
+                res.query = self.query.and(other.query);
+                return res;
+            }
+        }
+
+        // Every match contains a suffix of the first regex followed by a prefix of the second. If
+        // either side is exact, this is already captured by the new prefix or suffix set.
+        let boundary = match (&self.exact, &other.exact) {
+            (None, None) if self.suffix.len() * other.prefix.len() <= MAX_SET_SIZE => {
+                let strings = cross(&self.suffix, &other.prefix);
+                TrigramQuery::any_of(strings.iter().map(Vec::as_slice))
+            }
+            _ => TrigramQuery::All,
+        };
+        let prefix = match &self.exact {
+            Some(exact) => cross(exact, other.prefix()),
+            None => self.prefix.clone(),
+        };
+        let suffix = match &other.exact {
+            Some(exact) => cross(self.suffix(), exact),
+            None => other.suffix.clone(),
+        };
+
+        let (a, b) = (self.inexact(), other.inexact());
+        let mut res = Self {
+            exact: None,
+            prefix,
+            suffix,
+            query: a.query.and(b.query).and(boundary),
+        };
+        res.simplify();
+        res
+    }
+
+    fn alternate(self, other: Self) -> Self {
+        if let (Some(a), Some(b)) = (&self.exact, &other.exact) {
+            if a.len() + b.len() <= MAX_SET_SIZE {
+                let mut res = Self::exact(a.union(b).cloned().collect());
+                res.query = self.query.or(other.query);
+                return res;
+            }
+        }
+
+        let (a, b) = (self.inexact(), other.inexact());
+        let mut res = Self {
+            exact: None,
+            prefix: a.prefix.union(&b.prefix).cloned().collect(),
+            suffix: a.suffix.union(&b.suffix).cloned().collect(),
+            query: a.query.or(b.query),
+        };
+        res.simplify();
+        res
+    }
+
+    // Converts the info into the final query that every matching document satisfies
+    fn into_query(self) -> TrigramQuery {
+        match self.exact {
+            Some(exact) => self
+                .query
+                .and(TrigramQuery::any_of(exact.iter().map(Vec::as_slice))),
+            None => self
+                .query
+                .and(TrigramQuery::any_of(self.prefix.iter().map(Vec::as_slice)))
+                .and(TrigramQuery::any_of(self.suffix.iter().map(Vec::as_slice))),
+        }
+    }
+}
+
+// Returns every concatenation of a string from the first set with a string from the second
+fn cross(a: &StringSet, b: &StringSet) -> StringSet {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| [x.as_slice(), y.as_slice()].concat()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use TrigramQuery::*;
+
+    fn lit(s: &str) -> TrigramQuery {
+        Literal(s.as_bytes().to_vec())
+    }
+
+    fn query(pattern: &str) -> TrigramQuery {
+        RegexQuery::new(pattern).unwrap().trigram_query().clone()
+    }
+
+    #[test]
+    fn test_literal() {
+        assert_eq!(query("abc"), lit("abc"));
+        assert_eq!(query("ab"), All);
+        assert_eq!(
+            query("handle_request_timeout"),
+            lit("handle_request_timeout")
+        );
+    }
+
+    #[test]
+    fn test_alternation() {
+        assert_eq!(
+            query("spawn|block_on"),
+            Or(vec![lit("block_on"), lit("spawn")])
+        );
+        assert_eq!(query("abc|d"), All);
+        assert_eq!(
+            query("fn (spawn|block_on)\\("),
+            Or(vec![lit("fn block_on("), lit("fn spawn(")])
+        );
+    }
+
+    #[test]
+    fn test_concat_with_unknown() {
+        assert_eq!(query("foo.*bar"), And(vec![lit("foo"), lit("bar")]));
+        assert_eq!(
+            query("fn [a-z_]+\\(&self"),
+            And(vec![lit("fn "), lit("(&self")])
+        );
+        assert_eq!(query("a.*b"), All);
+    }
+
+    #[test]
+    fn test_repetition() {
+        assert_eq!(query("(abc)?def"), lit("def"));
+        assert_eq!(query("(abc)+def"), lit("abcdef"));
+        assert_eq!(query("x*yz"), All);
+    }
+
+    #[test]
+    fn test_small_class() {
+        assert_eq!(query("[Tt]okio"), Or(vec![lit("Tokio"), lit("tokio")]));
+        assert_eq!(
+            query("(?i)abc"),
+            TrigramQuery::any_of(
+                ["ABC", "ABc", "AbC", "Abc", "aBC", "aBc", "abC", "abc"]
+                    .iter()
+                    .map(|s| s.as_bytes())
+            )
+        );
+    }
+
+    #[test]
+    fn test_is_match() {
+        let q = RegexQuery::new("fn [a-z_]+\\(&self").unwrap();
+        assert!(q.is_match(b"pub fn search(&self) {}"));
+        assert!(!q.is_match(b"pub fn new() -> Self {}"));
+    }
+}